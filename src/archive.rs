@@ -0,0 +1,58 @@
+use anyhow::{bail, Result};
+use std::{
+    fs::File,
+    io::{Seek, Write},
+    path::Path,
+};
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+/// A file in the archive, the path is relative to the archive root with `/` separators
+pub struct Entry {
+    pub path: String,
+    pub content: Vec<u8>,
+    /// Permissions of the file, the ones the output would be written with
+    pub mode: u32,
+}
+
+/// Write the entries into a tar or zip archive chosen by the extension, `-` streams a tar to
+/// stdout
+pub fn write_archive(path: &Path, entries: &[Entry]) -> Result<()> {
+    if path == Path::new("-") {
+        return write_tar(std::io::stdout().lock(), entries);
+    }
+    let ext = path.extension().unwrap_or_default().to_ascii_lowercase();
+    if ext == "tar" {
+        write_tar(File::create(path)?, entries)
+    } else if ext == "zip" {
+        write_zip(File::create(path)?, entries)
+    } else {
+        bail!(
+            "Unknown archive format {}, expecting .tar or .zip",
+            path.to_string_lossy()
+        )
+    }
+}
+
+fn write_tar<W: Write>(w: W, entries: &[Entry]) -> Result<()> {
+    let mut builder = tar::Builder::new(w);
+    for entry in entries {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(entry.content.len() as u64);
+        header.set_mode(entry.mode);
+        header.set_cksum();
+        builder.append_data(&mut header, &entry.path, &entry.content[..])?;
+    }
+    builder.into_inner()?.flush()?;
+    Ok(())
+}
+
+fn write_zip<W: Write + Seek>(w: W, entries: &[Entry]) -> Result<()> {
+    let mut zip = ZipWriter::new(w);
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+    for entry in entries {
+        zip.start_file(&entry.path, options.unix_permissions(entry.mode))?;
+        zip.write_all(&entry.content)?;
+    }
+    zip.finish()?;
+    Ok(())
+}
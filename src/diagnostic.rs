@@ -0,0 +1,59 @@
+use std::fmt::Write;
+
+const RED: &str = "\x1b[1;31m";
+const BLUE: &str = "\x1b[1;34m";
+const RESET: &str = "\x1b[0m";
+
+/// Lines of source shown before the failing line
+const CONTEXT_LINES: usize = 2;
+
+/// Format a template error with its location and the surrounding lines of the template source,
+/// `line` and `column` are 1-based
+pub fn report(
+    name: &str,
+    message: &str,
+    location: Option<(usize, usize)>,
+    source: Option<&str>,
+    color: bool,
+) -> String {
+    let (red, blue, reset) = if color { (RED, BLUE, RESET) } else { ("", "", "") };
+    let mut out = format!("{}error{}: template {}: {}", red, reset, name, message);
+    let (line, column) = match location {
+        Some(location) => location,
+        None => return out,
+    };
+    let _ = write!(out, "\n  {}-->{} {}:{}:{}", blue, reset, name, line, column);
+    let source = match source {
+        Some(source) => source,
+        None => return out,
+    };
+    let width = line.to_string().len();
+    let first = line.saturating_sub(CONTEXT_LINES).max(1);
+    for (no, text) in source.lines().enumerate().map(|(i, l)| (i + 1, l)) {
+        if no < first {
+            continue;
+        }
+        if no > line {
+            break;
+        }
+        let _ = write!(out, "\n{}{:>w$} |{} {}", blue, no, reset, text, w = width);
+        if no == line {
+            let pad = " ".repeat(column.saturating_sub(1));
+            let _ = write!(out, "\n{}{:>w$} |{} {}{}^{}", blue, "", reset, pad, red, reset, w = width);
+        }
+    }
+    out
+}
+
+/// Format an error as a GitHub Actions workflow command, shown as an annotation on the file in
+/// the workflow run, `line` and `column` are 1-based
+pub fn github_annotation(file: &str, location: Option<(usize, usize)>, message: &str) -> String {
+    let escape = |s: &str| s.replace('%', "%25").replace('\r', "%0D").replace('\n', "%0A");
+    let property = |s: &str| escape(s).replace(':', "%3A").replace(',', "%2C");
+    let mut out = format!("::error file={}", property(file));
+    if let Some((line, column)) = location {
+        let _ = write!(out, ",line={},col={}", line, column);
+    }
+    let _ = write!(out, "::{}", escape(message));
+    out
+}
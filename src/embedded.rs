@@ -0,0 +1,38 @@
+use serde_json::Value;
+
+/// Templates from `embedded/templates`, read from the disk in debug builds
+#[cfg(feature = "embedded")]
+#[derive(rust_embed::RustEmbed)]
+#[folder = "embedded/templates"]
+struct Templates;
+
+/// The values of `embedded/values.json`, `None` without the `embedded` feature
+pub fn values() -> Option<Value> {
+    #[cfg(feature = "embedded")]
+    {
+        use crate::Logged;
+        let values = include_str!("../embedded/values.json");
+        serde_json::from_str(values)
+            .map_err(|e| format!("Invalid embedded values, {}", e))
+            .log()
+            .ok()
+    }
+    #[cfg(not(feature = "embedded"))]
+    None
+}
+
+/// Path relative to `embedded/templates` and source of each embedded file
+pub fn templates() -> Vec<(String, String)> {
+    #[cfg(feature = "embedded")]
+    {
+        Templates::iter()
+            .filter_map(|path| {
+                let file = Templates::get(&path)?;
+                let source = String::from_utf8_lossy(&file.data).into_owned();
+                Some((path.into_owned(), source))
+            })
+            .collect()
+    }
+    #[cfg(not(feature = "embedded"))]
+    vec![]
+}
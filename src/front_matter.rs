@@ -0,0 +1,59 @@
+use log::debug;
+use serde::Deserialize;
+
+/// Directives in the YAML block at the top of a template, delimited by `---` lines
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(rename_all = "camelCase", default, deny_unknown_fields)]
+pub struct FrontMatter {
+    /// Only generate the template when this key is truthy in the data, or when this
+    /// handlebars expression renders to a truthy string
+    pub when: Option<String>,
+    /// Render the template once per item of the array under this key, with the item as context
+    pub for_each: Option<String>,
+    /// Render the template once per chunk of this many `forEach` items instead, with the array
+    /// of items as context and `@index` the index of the chunk, the last one may be shorter
+    pub chunk_size: Option<usize>,
+    /// Extension of the output like `.conf`, replacing the extension left in the template name
+    pub ext: Option<String>,
+    /// Output path template of each `forEach` item, relative to the output directory
+    pub output: Option<String>,
+    /// Render the template against the data flattened into dotted keys, like `server.port`
+    pub flatten_data: bool,
+    /// Templates are rendered, concatenated and indexed by increasing weight, then by name
+    pub weight: i64,
+    /// Octal permissions of the output like `0600`, or symbolic like `u+x` applied to
+    /// `--chmod` or the default permissions, overrides `--chmod`
+    pub mode: Option<String>,
+    /// Dotted data paths that must be present and non-empty, the template is skipped otherwise
+    pub requires: Vec<String>,
+}
+
+/// Split the front matter from the template source
+///
+/// The source is returned untouched if it doesn't start with a `---` line, or if the block
+/// is not valid front matter, so YAML templates starting with a document marker still work.
+pub fn split(source: &str) -> (FrontMatter, &str) {
+    let rest = match source
+        .strip_prefix("---\n")
+        .or_else(|| source.strip_prefix("---\r\n"))
+    {
+        Some(rest) => rest,
+        None => return (FrontMatter::default(), source),
+    };
+    let mut offset = 0;
+    for line in rest.split_inclusive('\n') {
+        if line.trim_end() == "---" {
+            let block = &rest[..offset];
+            let body = &rest[offset + line.len()..];
+            return match serde_yaml::from_str::<Option<FrontMatter>>(block) {
+                Ok(fm) => (fm.unwrap_or_default(), body),
+                Err(e) => {
+                    debug!("Not a front matter block, {}", e);
+                    (FrontMatter::default(), source)
+                }
+            };
+        }
+        offset += line.len();
+    }
+    (FrontMatter::default(), source)
+}
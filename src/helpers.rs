@@ -0,0 +1,1329 @@
+use chrono::{DateTime, Local, Utc};
+use handlebars::{
+    handlebars_helper, Context, Handlebars, Helper, HelperDef, HelperResult, Output,
+    RenderContext, RenderError, ScopedJson,
+};
+use log::{debug, warn};
+use serde_json::Value;
+use std::{
+    cell::Cell,
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{Arc, Mutex, OnceLock, RwLock},
+};
+
+/// State of the built-in helpers registered in one registry, so generators in the same process
+/// don't share their strictness, random secrets, globals or template sources
+#[derive(Debug, Default)]
+pub struct HelperState {
+    /// Make helper misuse, like an invalid value or an unreadable file, a render error instead
+    /// of a warning
+    strict: bool,
+    /// Values generated by `randomSecret` by name, loaded from and saved to `--seed-file`
+    seeds: Mutex<BTreeMap<String, String>>,
+    /// Values shared between templates by `setGlobal`
+    globals: Mutex<BTreeMap<String, Value>>,
+    /// Source file of each template and partial, relative includes are resolved from its
+    /// directory
+    sources: RwLock<BTreeMap<String, PathBuf>>,
+}
+
+impl HelperState {
+    pub fn new(strict: bool) -> Self {
+        Self {
+            strict,
+            ..Default::default()
+        }
+    }
+
+    /// Set the values returned by `randomSecret` for the names already generated
+    pub fn set_seeds(&self, seeds: BTreeMap<String, String>) {
+        *self.seeds.lock().unwrap() = seeds;
+    }
+
+    /// Values of `randomSecret` by name, including the ones generated by this run
+    pub fn seeds(&self) -> BTreeMap<String, String> {
+        self.seeds.lock().unwrap().clone()
+    }
+
+    /// Forget the values stored by `setGlobal`, before rendering the templates again
+    pub fn reset_globals(&self) {
+        self.globals.lock().unwrap().clear();
+    }
+
+    /// Set the source files of the registered templates
+    pub fn set_template_sources(&self, sources: BTreeMap<String, PathBuf>) {
+        *self.sources.write().unwrap() = sources;
+    }
+}
+
+thread_local! {
+    /// Strictness of the registry of the helper being called, set by `Scoped`
+    static STRICT: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Built-in helper registered with the state of its registry, its misuse fails the render
+/// when the registry is strict
+struct Scoped {
+    helper: Box<dyn HelperDef + Send + Sync>,
+    state: Arc<HelperState>,
+}
+
+impl Scoped {
+    /// Call the helper with the strictness of its registry, then restore the one of the helper
+    /// calling it, if any
+    fn scope<T>(&self, f: impl FnOnce(&dyn HelperDef) -> T) -> T {
+        let outer = STRICT.replace(self.state.strict);
+        let result = f(self.helper.as_ref());
+        STRICT.set(outer);
+        result
+    }
+}
+
+impl HelperDef for Scoped {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        r: &'reg Handlebars<'reg>,
+        ctx: &'rc Context,
+        rc: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'reg, 'rc>, RenderError> {
+        self.scope(|helper| helper.call_inner(h, r, ctx, rc))
+    }
+
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        r: &'reg Handlebars<'reg>,
+        ctx: &'rc Context,
+        rc: &mut RenderContext<'reg, 'rc>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        self.scope(|helper| helper.call(h, r, ctx, rc, out))
+    }
+}
+
+/// Fail the render in strict mode, otherwise warn and let the helper write its fallback
+fn soft_fail(msg: String) -> Result<(), RenderError> {
+    if STRICT.get() {
+        Err(RenderError::new(msg))
+    } else {
+        warn!("{}", msg);
+        Ok(())
+    }
+}
+
+/// `handlebars_helper!` reading its params by hand, a missing or wrongly typed param is a
+/// `soft_fail` after which the helper writes nothing, a value missing with `--strict` is still
+/// an error
+macro_rules! soft_helper {
+    ($struct_name:ident: |$($name:ident: $tpe:tt),*
+     $($(,)?{$($hash_name:ident: $hash_tpe:tt=$dft_val:literal),*})?
+     $($(,)?*$args:ident)?|
+     $body:expr ) => {
+        #[allow(non_camel_case_types)]
+        pub struct $struct_name;
+
+        impl HelperDef for $struct_name {
+            #[allow(unused_assignments, unused_mut, unused_variables)]
+            fn call_inner<'reg: 'rc, 'rc>(
+                &self,
+                h: &Helper<'reg, 'rc>,
+                r: &'reg Handlebars<'reg>,
+                _: &'rc Context,
+                _: &mut RenderContext<'reg, 'rc>,
+            ) -> Result<ScopedJson<'reg, 'rc>, RenderError> {
+                let mut param_idx = 0;
+                $(
+                    let param = h.param(param_idx);
+                    if r.strict_mode() && param.is_some_and(|p| p.is_value_missing()) {
+                        let msg = format!("{}: missing value of {}", h.name(), stringify!($name));
+                        return Err(RenderError::new(msg));
+                    }
+                    let $name = match param.map(|p| p.value()) {
+                        Some(v) => match soft_helper!(@as v, $tpe) {
+                            Some(v) => v,
+                            None => {
+                                soft_fail(format!(
+                                    "{}: {} should be a {}, got {}",
+                                    h.name(),
+                                    stringify!($name),
+                                    stringify!($tpe),
+                                    v
+                                ))?;
+                                return Ok(ScopedJson::Derived(Value::Null));
+                            }
+                        },
+                        None => {
+                            soft_fail(format!("{}: missing {}", h.name(), stringify!($name)))?;
+                            return Ok(ScopedJson::Derived(Value::Null));
+                        }
+                    };
+                    param_idx += 1;
+                )*
+                $($(
+                    let $hash_name = match h.hash_get(stringify!($hash_name)).map(|p| p.value()) {
+                        Some(v) => match soft_helper!(@as v, $hash_tpe) {
+                            Some(v) => v,
+                            None => {
+                                soft_fail(format!(
+                                    "{}: {} should be a {}, got {}",
+                                    h.name(),
+                                    stringify!($hash_name),
+                                    stringify!($hash_tpe),
+                                    v
+                                ))?;
+                                return Ok(ScopedJson::Derived(Value::Null));
+                            }
+                        },
+                        None => $dft_val,
+                    };
+                )*)?
+                $(let $args = h.params().iter().map(|p| p.value()).collect::<Vec<&Value>>();)?
+                let result = $body;
+                Ok(ScopedJson::Derived(Value::from(result)))
+            }
+        }
+    };
+    (@as $x:ident, str) => { $x.as_str() };
+    (@as $x:ident, i64) => { $x.as_i64() };
+    (@as $x:ident, u64) => { $x.as_u64() };
+    (@as $x:ident, bool) => { $x.as_bool() };
+    (@as $x:ident, Json) => { Some($x) };
+}
+
+/// Git metadata of the current directory, looked up once per run
+struct GitInfo {
+    commit: String,
+    branch: String,
+    dirty: bool,
+}
+
+/// Trimmed standard output of a successful git command
+pub(crate) fn git(args: &[&str]) -> Option<String> {
+    let output = Command::new("git").args(args).output().ok()?;
+    if output.status.success() {
+        Some(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+    } else {
+        None
+    }
+}
+
+fn git_info() -> Option<&'static GitInfo> {
+    static INFO: OnceLock<Option<GitInfo>> = OnceLock::new();
+    INFO.get_or_init(|| {
+        let info = git(&["rev-parse", "--short", "HEAD"]).map(|commit| GitInfo {
+            commit,
+            branch: git(&["rev-parse", "--abbrev-ref", "HEAD"]).unwrap_or_default(),
+            dirty: git(&["status", "--porcelain"])
+                .map(|s| !s.is_empty())
+                .unwrap_or_default(),
+        });
+        if info.is_none() {
+            warn!("Not in a git repository, git helpers will output `unknown`");
+        }
+        info
+    })
+    .as_ref()
+}
+
+handlebars_helper!(git_commit: | | git_info().map(|i| i.commit.as_str()).unwrap_or("unknown"));
+handlebars_helper!(git_branch: | | git_info().map(|i| i.branch.as_str()).unwrap_or("unknown"));
+handlebars_helper!(git_dirty: | | git_info().map(|i| i.dirty).unwrap_or_default());
+
+/// Seconds since the Unix epoch in `SOURCE_DATE_EPOCH`, for reproducible builds
+pub(crate) fn source_date_epoch() -> Option<i64> {
+    std::env::var("SOURCE_DATE_EPOCH").ok().and_then(|s| {
+        let ts = s.trim().parse().ok();
+        if ts.is_none() {
+            warn!("Invalid SOURCE_DATE_EPOCH {}", s);
+        }
+        ts
+    })
+}
+
+/// Current time, or the time in `SOURCE_DATE_EPOCH` for reproducible builds
+fn now() -> DateTime<Utc> {
+    source_date_epoch()
+        .and_then(|ts| DateTime::from_timestamp(ts, 0))
+        .unwrap_or_else(Utc::now)
+}
+
+/// Format with a strftime pattern, `None` if the pattern is invalid
+fn format_time<Tz>(t: &DateTime<Tz>, fmt: &str) -> Option<String>
+where
+    Tz: chrono::TimeZone,
+    Tz::Offset: std::fmt::Display,
+{
+    use std::fmt::Write;
+    let mut s = String::new();
+    write!(s, "{}", t.format(fmt)).ok()?;
+    Some(s)
+}
+
+// `{{now}}` writes RFC 3339, `{{now "%Y-%m-%d"}}` uses a strftime pattern, `local=true` uses local timezone
+soft_helper!(now_helper: |{local: bool = false}, *args| {
+    let fmt = args.first().and_then(|v| v.as_str());
+    let now = now();
+    let formatted = match (fmt, local) {
+        (Some(fmt), false) => format_time(&now, fmt),
+        (Some(fmt), true) => format_time(&now.with_timezone(&Local), fmt),
+        (None, false) => Some(now.to_rfc3339()),
+        (None, true) => Some(now.with_timezone(&Local).to_rfc3339()),
+    };
+    match formatted {
+        Some(s) => s,
+        None => {
+            soft_fail(format!("now: invalid time format {}", fmt.unwrap_or_default()))?;
+            String::new()
+        }
+    }
+});
+
+const IEC_UNITS: [&str; 7] = ["", "Ki", "Mi", "Gi", "Ti", "Pi", "Ei"];
+
+/// Read a number from a JSON number or a numeric string
+fn as_f64(v: &Value) -> Option<f64> {
+    match v {
+        Value::Number(n) => n.as_f64(),
+        Value::String(s) => s.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+/// Format a byte count with IEC units, `precision` decimals or up to 2 with trailing zeros trimmed
+fn human_bytes(bytes: f64, precision: Option<usize>) -> String {
+    let mut value = bytes;
+    let mut unit = 0;
+    while value.abs() >= 1024.0 && unit < IEC_UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    let s = match precision {
+        Some(p) => format!("{:.*}", p, value),
+        None => {
+            let s = format!("{:.2}", value);
+            s.trim_end_matches('0').trim_end_matches('.').to_owned()
+        }
+    };
+    format!("{}{}", s, IEC_UNITS[unit])
+}
+
+/// Parse a size like `1Gi`, `512Mi`, `1.5G` or `1024` into bytes
+pub(crate) fn parse_bytes(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+    let num: f64 = num.parse().ok()?;
+    let unit = unit.trim().trim_end_matches('B');
+    let multiplier = match unit {
+        "" => 1f64,
+        "k" | "K" => 1e3,
+        "M" => 1e6,
+        "G" => 1e9,
+        "T" => 1e12,
+        "P" => 1e15,
+        "E" => 1e18,
+        _ => {
+            let pos = IEC_UNITS
+                .iter()
+                .position(|u| !u.is_empty() && unit == *u)?;
+            1024f64.powi(pos as i32)
+        }
+    };
+    Some((num * multiplier).round() as u64)
+}
+
+// `{{humanBytes 1073741824}}` writes `1Gi`, an optional second param sets the precision
+soft_helper!(human_bytes_helper: |v: Json, *args| {
+    let precision = args.get(1).and_then(|p| p.as_u64()).map(|p| p as usize);
+    match as_f64(v) {
+        Some(n) => human_bytes(n, precision),
+        None => {
+            soft_fail(format!("humanBytes: {} is not a number", v))?;
+            String::new()
+        }
+    }
+});
+
+// `{{parseBytes "1Gi"}}` returns `1073741824`
+soft_helper!(parse_bytes_helper: |v: Json| {
+    match v {
+        Value::Number(_) => v.clone(),
+        Value::String(s) => match parse_bytes(s) {
+            Some(n) => Value::from(n),
+            None => {
+                soft_fail(format!("parseBytes: cannot parse {}", s))?;
+                Value::Null
+            }
+        },
+        _ => {
+            soft_fail(format!("parseBytes: cannot parse {}", v))?;
+            Value::Null
+        }
+    }
+});
+
+/// Group the integer digits of a formatted number by thousands
+fn group_thousands(formatted: &str, sep: &str) -> String {
+    let (sign, rest) = match formatted.strip_prefix('-') {
+        Some(rest) => ("-", rest),
+        None => ("", formatted),
+    };
+    let (int, frac) = match rest.find('.') {
+        Some(i) => rest.split_at(i),
+        None => (rest, ""),
+    };
+    let mut grouped = String::with_capacity(formatted.len() + int.len() / 3 * sep.len());
+    for (i, c) in int.chars().enumerate() {
+        if i > 0 && (int.len() - i) % 3 == 0 {
+            grouped.push_str(sep);
+        }
+        grouped.push(c);
+    }
+    format!("{}{}{}", sign, grouped, frac)
+}
+
+// `{{numberFormat 1000000}}` writes `1,000,000`, optional params set the separator and the
+// number of decimals, `{{numberFormat 1234.5 " " 2}}` writes `1 234.50`
+soft_helper!(number_format: |v: Json, *args| {
+    let sep = args.get(1).and_then(|s| s.as_str()).unwrap_or(",");
+    let decimals = args.get(2).and_then(|d| d.as_u64()).map(|d| d as usize);
+    let formatted = match (v, decimals) {
+        (Value::Number(n), None) => Some(n.to_string()),
+        (v, Some(d)) => as_f64(v).map(|n| format!("{:.*}", d, n)),
+        (Value::String(s), None) => s.trim().parse::<f64>().ok().map(|_| s.trim().to_owned()),
+        _ => None,
+    };
+    match formatted {
+        Some(f) => Value::from(group_thousands(&f, sep)),
+        None => {
+            soft_fail(format!("numberFormat: {} is not a number", v))?;
+            v.clone()
+        }
+    }
+});
+
+// `{{formatDuration 90}}` writes `1m30s`, fractions of seconds are kept down to milliseconds
+#[cfg(feature = "durations")]
+soft_helper!(format_duration: |v: Json| {
+    match as_f64(v).filter(|s| s.is_finite() && *s >= 0.0) {
+        Some(secs) => {
+            let d = std::time::Duration::from_millis((secs * 1000.0).round() as u64);
+            Value::from(humantime::format_duration(d).to_string().replace(' ', ""))
+        }
+        None => {
+            soft_fail(format!("formatDuration: {} is not a number of seconds", v))?;
+            v.clone()
+        }
+    }
+});
+
+// `{{parseDuration "1h30m"}}` returns `5400`, `{{parseDuration "500ms"}}` returns `0.5`
+#[cfg(feature = "durations")]
+soft_helper!(parse_duration: |v: Json| {
+    match v.as_str().map(|s| humantime::parse_duration(s.trim())) {
+        Some(Ok(d)) if d.subsec_nanos() == 0 => Value::from(d.as_secs()),
+        Some(Ok(d)) => Value::from(d.as_secs_f64()),
+        Some(Err(e)) => {
+            soft_fail(format!("parseDuration: cannot parse {}, {}", v, e))?;
+            v.clone()
+        }
+        None => {
+            soft_fail(format!("parseDuration: {} is not a string", v))?;
+            v.clone()
+        }
+    }
+});
+
+/// Capitalize the first letter of each word and lowercase the rest
+fn title_case(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut start = true;
+    for c in s.chars() {
+        if start {
+            out.extend(c.to_uppercase());
+        } else {
+            out.extend(c.to_lowercase());
+        }
+        start = !c.is_alphanumeric();
+    }
+    out
+}
+
+soft_helper!(uppercase: |s: str| s.to_uppercase());
+soft_helper!(lowercase: |s: str| s.to_lowercase());
+soft_helper!(titlecase: |s: str| title_case(s));
+soft_helper!(trim: |s: str| s.trim());
+soft_helper!(replace: |s: str, from: str, to: str| s.replace(from, to));
+
+// `{{relPath outputDir assetPath}}` writes the path of the target relative to the base
+// directory with `/` separators, relative paths are resolved from the current directory
+#[cfg(feature = "relpath")]
+soft_helper!(rel_path: |base: str, target: str| {
+    let base = crate::normalize_path(Path::new(base));
+    let target = crate::normalize_path(Path::new(target));
+    let rel = pathdiff::diff_paths(&target, &base).unwrap_or(target);
+    let parts: Vec<_> = rel.components().map(|c| c.as_os_str().to_string_lossy()).collect();
+    if parts.is_empty() {
+        ".".to_owned()
+    } else {
+        parts.join("/")
+    }
+});
+
+// `{{default v "x"}}` writes `x` when `v` is missing, with `boolean=true` also when `v` is falsy
+soft_helper!(default: |v: Json, d: Json, {boolean: bool = false}| {
+    let use_default = match v {
+        Value::Null => true,
+        Value::Bool(b) => boolean && !b,
+        Value::Number(n) => boolean && n.as_f64() == Some(0.0),
+        Value::String(s) => boolean && s.is_empty(),
+        Value::Array(a) => boolean && a.is_empty(),
+        Value::Object(m) => boolean && m.is_empty(),
+    };
+    if use_default { d.clone() } else { v.clone() }
+});
+
+// Number of characters of a string, items of an array or entries of an object
+soft_helper!(length: |v: Json| match v {
+    Value::String(s) => s.chars().count(),
+    Value::Array(a) => a.len(),
+    Value::Object(m) => m.len(),
+    _ => 0,
+});
+
+/// Singular and plural of the irregular words `inflector` gets wrong
+#[cfg(feature = "inflection")]
+const IRREGULAR: &[(&str, &str)] = &[
+    ("person", "people"),
+    ("man", "men"),
+    ("woman", "women"),
+    ("child", "children"),
+    ("tooth", "teeth"),
+    ("foot", "feet"),
+    ("goose", "geese"),
+    ("mouse", "mice"),
+    ("ox", "oxen"),
+];
+
+/// Plural or singular of a word, keeping the case of its first letter
+#[cfg(feature = "inflection")]
+fn inflect(word: &str, plural: bool) -> String {
+    let lower = word.to_lowercase();
+    let irregular = IRREGULAR
+        .iter()
+        .find(|(one, many)| lower == *one || lower == *many)
+        .map(|(one, many)| if plural { *many } else { *one });
+    match irregular {
+        Some(form) if word.starts_with(char::is_uppercase) => {
+            let mut chars = form.chars();
+            chars.next().map(|c| c.to_uppercase().chain(chars).collect()).unwrap_or_default()
+        }
+        Some(form) => form.to_owned(),
+        None if plural => inflector::string::pluralize::to_plural(word),
+        None => inflector::string::singularize::to_singular(word),
+    }
+}
+
+// `{{pluralize "item"}}` is `items`, `{{pluralize count "item"}}` keeps the word as is when
+// the count is 1, irregular words like `person` and `child` are handled
+#[cfg(feature = "inflection")]
+handlebars_helper!(pluralize: |*args| {
+    let (count, word) = match args.as_slice() {
+        [Value::String(word)] => (None, word),
+        [count, Value::String(word)] => (count.as_f64(), word),
+        _ => {
+            soft_fail("pluralize: expecting a word, optionally after a count".to_owned())?;
+            return Ok(ScopedJson::Derived(Value::String(String::new())));
+        }
+    };
+    match count {
+        Some(count) if count.abs() == 1.0 => word.clone(),
+        _ => inflect(word, true),
+    }
+});
+
+// `{{singularize "categories"}}` is `category`
+#[cfg(feature = "inflection")]
+soft_helper!(singularize: |s: str| inflect(s, false));
+
+/// Sort the keys of the objects in the value, recursively, so serializing it doesn't depend on
+/// the order the keys were inserted in
+pub(crate) fn sort_keys(v: Value) -> Value {
+    match v {
+        Value::Object(m) => {
+            let mut entries: Vec<_> = m.into_iter().collect();
+            entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+            Value::Object(entries.into_iter().map(|(k, v)| (k, sort_keys(v))).collect())
+        }
+        Value::Array(a) => Value::Array(a.into_iter().map(sort_keys).collect()),
+        v => v,
+    }
+}
+
+// `{{{toJsonCompact (sortKeys labels)}}}` serializes the value with the keys of its objects
+// sorted
+soft_helper!(sort_keys_helper: |v: Json| sort_keys(v.clone()));
+
+// `{{{toJsonCompact labels}}}` serializes a value as JSON on a single line, with the quotes
+// and control characters of strings escaped, use the triple-stash to skip HTML escaping
+soft_helper!(to_json_compact: |v: Json| v.to_string());
+
+/// 64-bit FNV-1a with a final mix of the bits, stable across platforms and Rust versions
+/// unlike `DefaultHasher`
+fn seed_hash(s: &str) -> u64 {
+    let h = s
+        .bytes()
+        .fold(0xcbf2_9ce4_8422_2325, |h, b| (h ^ b as u64).wrapping_mul(0x0100_0000_01b3));
+    let h = (h ^ (h >> 33)).wrapping_mul(0xff51_afd7_ed55_8ccd);
+    h ^ (h >> 33)
+}
+
+// `{{sample colors "seed"}}` picks an item of the array, the same seed always picks the same
+// item of the same array so outputs don't change between runs, an empty seed when omitted
+soft_helper!(sample: |v: Json, *args| {
+    let seed = match args.get(1) {
+        Some(Value::String(s)) => s.clone(),
+        Some(seed) => seed.to_string(),
+        None => String::new(),
+    };
+    match v {
+        Value::Array(a) if !a.is_empty() => a[(seed_hash(&seed) % a.len() as u64) as usize].clone(),
+        Value::Array(_) | Value::Null => Value::Null,
+        _ => {
+            soft_fail(format!("sample: {} is not an array", v))?;
+            Value::Null
+        }
+    }
+});
+
+/// Random alphanumeric string from the OS generator
+#[cfg(feature = "random")]
+fn random_string(len: usize) -> Result<String, getrandom::Error> {
+    const CHARS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+    let mut out = String::with_capacity(len);
+    let mut buf = [0u8; 64];
+    while out.len() < len {
+        getrandom::getrandom(&mut buf)?;
+        // Bytes over the largest multiple of the alphabet size are dropped to avoid a bias
+        let limit = 256 - 256 % CHARS.len();
+        let chars = buf.iter().filter(|b| usize::from(**b) < limit);
+        out.extend(chars.map(|b| CHARS[usize::from(*b) % CHARS.len()] as char));
+    }
+    out.truncate(len);
+    Ok(out)
+}
+
+/// `{{randomSecret "db_password" 32}}` is a random alphanumeric string generated once per name,
+/// later runs return the same value when it's stored with `--seed-file`, the length only
+/// applies when the value is generated
+#[cfg(feature = "random")]
+struct RandomSecret(Arc<HelperState>);
+
+#[cfg(feature = "random")]
+impl HelperDef for RandomSecret {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'reg, 'rc>, RenderError> {
+        let name = h.param(0).and_then(|p| p.value().as_str());
+        let len = h.param(1).and_then(|p| p.value().as_u64());
+        let (name, len) = name
+            .zip(len)
+            .ok_or_else(|| RenderError::new("randomSecret: expecting a name and a length"))?;
+        let mut seeds = self.0.seeds.lock().unwrap();
+        if !seeds.contains_key(name) {
+            let secret = random_string(len as usize)
+                .map_err(|e| RenderError::new(format!("randomSecret: {}", e)))?;
+            seeds.insert(name.to_owned(), secret);
+        }
+        Ok(ScopedJson::Derived(Value::String(seeds[name].clone())))
+    }
+}
+
+// `{{at items -1}}` is the last item, an optional third param is the value when out of range
+soft_helper!(at: |v: Json, index: i64, *args| {
+    let fallback = args.get(2).map(|d| (*d).clone()).unwrap_or_default();
+    match v {
+        Value::Array(a) => {
+            let i = if index < 0 { a.len() as i64 + index } else { index };
+            usize::try_from(i).ok().and_then(|i| a.get(i)).cloned().unwrap_or(fallback)
+        }
+        _ => {
+            soft_fail(format!("at: {} is not an array", v))?;
+            fallback
+        }
+    }
+});
+
+// `{{jsonpath config "$.servers[?(@.primary)].host"}}` is the first match of the JSONPath
+// expression, `all=true` is the array of all the matches
+#[cfg(feature = "jsonpath")]
+soft_helper!(jsonpath: |v: Json, path: str, {all: bool = false}| {
+    match serde_json_path::JsonPath::parse(path).map(|p| p.query(v).all()) {
+        Ok(found) if all => Value::Array(found.into_iter().cloned().collect()),
+        Ok(found) => found.first().map(|v| (*v).clone()).unwrap_or_default(),
+        Err(e) => {
+            soft_fail(format!("jsonpath: invalid expression {}, {}", path, e))?;
+            Value::Null
+        }
+    }
+});
+
+/// Longest array `range` produces
+const MAX_RANGE: usize = 10_000;
+
+// `{{#each (range 1 4)}}` iterates over `[1, 2, 3]`, an optional third param is the step
+soft_helper!(range: |start: i64, end: i64, *args| {
+    let step = args.get(2).and_then(|s| s.as_i64()).unwrap_or(1);
+    if step == 0 {
+        soft_fail("range: step cannot be 0".to_owned())?;
+        return Ok(handlebars::ScopedJson::Derived(Value::Array(vec![])));
+    }
+    let items: Vec<i64> = std::iter::successors(Some(start), |i| i.checked_add(step))
+        .take_while(|i| if step > 0 { *i < end } else { *i > end })
+        .take(MAX_RANGE + 1)
+        .collect();
+    if items.len() > MAX_RANGE {
+        warn!("range: {}..{} is longer than {} items, clamped", start, end, MAX_RANGE);
+    }
+    items.into_iter().take(MAX_RANGE).collect::<Vec<_>>()
+});
+
+// `{{#with (mergeObjects defaults overrides)}}` deep merges objects, later params win
+handlebars_helper!(merge_objects: |*args| {
+    let mut merged = Value::Object(Default::default());
+    for v in args {
+        if v.is_object() {
+            crate::merge(&mut merged, v.clone());
+        } else {
+            soft_fail(format!("mergeObjects: {} is not an object, ignored", v))?;
+        }
+    }
+    merged
+});
+
+/// Percent-encode everything but the unreserved characters of RFC 3986
+fn percent_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            b => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Decode `%XX` escapes, `None` for truncated or invalid escapes and non UTF-8 results
+fn percent_decode(s: &str) -> Option<String> {
+    let mut bytes = vec![];
+    let mut rest = s.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        if b == b'%' {
+            let hex = tail.get(..2).filter(|h| h.iter().all(u8::is_ascii_hexdigit))?;
+            bytes.push(u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(b);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+// `{{urlEncode password}}` percent-encodes a URL component
+soft_helper!(url_encode: |s: str| percent_encode(s));
+
+// `{{urlDecode s}}` decodes percent escapes, malformed input writes nothing
+soft_helper!(url_decode: |s: str| match percent_decode(s) {
+    Some(decoded) => decoded,
+    None => {
+        soft_fail(format!("urlDecode: invalid escape in {}", s))?;
+        String::new()
+    }
+});
+
+// `{{{queryString params}}}` encodes an object as `k=v&k2=v2` with sorted keys, nested
+// objects and arrays are flattened into dotted keys like `filter.tags.0=a`
+soft_helper!(query_string: |v: Json| {
+    if !v.is_object() {
+        soft_fail(format!("queryString: {} is not an object", v))?;
+        return Ok(ScopedJson::Derived(Value::String(String::new())));
+    }
+    let mut flat = serde_json::Map::new();
+    crate::flatten(v, "", &mut flat);
+    flat.iter()
+        .map(|(k, v)| {
+            let v = match v {
+                Value::String(s) => s.clone(),
+                Value::Null => String::new(),
+                v => v.to_string(),
+            };
+            format!("{}={}", percent_encode(k), percent_encode(&v))
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+});
+
+// `{{#if (isTruthy x)}}` with `false`, `null`, `0`, `""`, `[]` and `{}` being falsy
+soft_helper!(is_truthy: |v: Json| match v {
+    Value::Null => false,
+    Value::Bool(b) => *b,
+    Value::Number(n) => n.as_f64() != Some(0.0),
+    Value::String(s) => !s.is_empty(),
+    Value::Array(a) => !a.is_empty(),
+    Value::Object(m) => !m.is_empty(),
+});
+
+/// `{{assert database.url "database.url is required"}}` writes the value, a missing or empty
+/// value fails the render with `--strict`, otherwise it warns and writes nothing
+struct Assert;
+
+impl HelperDef for Assert {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        r: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'reg, 'rc>, RenderError> {
+        let v = h.param(0).map(|p| p.value().clone()).unwrap_or_default();
+        let empty = match &v {
+            Value::Null => true,
+            Value::String(s) => s.is_empty(),
+            Value::Array(a) => a.is_empty(),
+            Value::Object(m) => m.is_empty(),
+            _ => false,
+        };
+        if !empty {
+            return Ok(ScopedJson::Derived(v));
+        }
+        let msg = h.param(1).and_then(|m| m.value().as_str());
+        let msg = format!("assert: {}", msg.unwrap_or("value is missing or empty"));
+        if r.strict_mode() {
+            return Err(RenderError::new(msg));
+        }
+        warn!("{}", msg);
+        Ok(ScopedJson::Derived(Value::Null))
+    }
+}
+
+// `{{commentIf disabled "# " "feature: on"}}` writes the content with each line commented out
+// when the condition is truthy like with `#if`, `unless=true` comments it out when it's falsy
+soft_helper!(comment_if: |cond: Json, prefix: str, content: str, {unless: bool = false}| {
+    if crate::is_truthy(cond) != unless {
+        content
+            .split('\n')
+            .map(|line| format!("{}{}", prefix, line))
+            .collect::<Vec<_>>()
+            .join("\n")
+    } else {
+        content.to_owned()
+    }
+});
+
+/// Format a value as a YAML scalar, strings are quoted when needed, arrays and objects use
+/// the flow style
+fn yaml_quote(v: &Value) -> String {
+    match v {
+        Value::String(_) => serde_yaml::to_string(v)
+            .map(|s| s.trim_start_matches("---").trim().to_owned())
+            .unwrap_or_default(),
+        _ => v.to_string(),
+    }
+}
+
+// `{{{yamlQuote name}}}` writes `"yes"` for the string `yes` so it isn't read back as a boolean
+soft_helper!(yaml_quote_helper: |v: Json| yaml_quote(v));
+
+// `{{{yamlKv "region" region}}}` writes `region: <value>` and a line break, or nothing when the
+// value is null or empty, an optional third param is the indentation. The line break is part
+// of the output so the helper is followed by `~}}}` or chained on the same line.
+soft_helper!(yaml_kv: |key: str, v: Json, *args| {
+    let present = match v {
+        Value::Null => false,
+        Value::String(s) => !s.is_empty(),
+        Value::Array(a) => !a.is_empty(),
+        Value::Object(m) => !m.is_empty(),
+        _ => true,
+    };
+    if present {
+        let indent = args.get(2).and_then(|i| i.as_u64()).unwrap_or(0) as usize;
+        format!("{}{}: {}\n", " ".repeat(indent), key, yaml_quote(v))
+    } else {
+        String::new()
+    }
+});
+
+/// Wrap each line at `width` columns on word boundaries, longer words are kept whole
+#[cfg(feature = "wrap")]
+fn wrap(s: &str, width: usize) -> String {
+    if width == 0 {
+        return s.to_owned();
+    }
+    let options = textwrap::Options::new(width).break_words(false);
+    s.split('\n')
+        .map(|line| textwrap::wrap(line, &options).join("\n"))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// `{{wrap description 80}}` wraps long text, existing line breaks are kept, a width of 0 writes
+// the text as is
+#[cfg(feature = "wrap")]
+soft_helper!(wrap_helper: |s: str, width: u64| wrap(s, width as usize));
+
+/// Indent every line after the first by `width` spaces or tabs, also the first with `first`
+fn indent(s: &str, width: usize, tab: bool, first: bool) -> String {
+    let pad = if tab { "\t" } else { " " }.repeat(width);
+    let indented = s.replace('\n', &format!("\n{}", pad));
+    if first {
+        format!("{}{}", pad, indented)
+    } else {
+        indented
+    }
+}
+
+/// Replace `auto` in `{{indent auto text}}` with the column of the tag in the template source,
+/// so the lines after the first line up with it
+pub fn resolve_auto_indent(source: &str) -> String {
+    static AUTO: OnceLock<regex::Regex> = OnceLock::new();
+    let re = AUTO.get_or_init(|| {
+        regex::Regex::new(r#"\{\{~?\s*indent\s+(auto|"auto")(\s|\}\})"#).expect("valid regex")
+    });
+    let mut out = String::with_capacity(source.len());
+    for line in source.split_inclusive('\n') {
+        let mut last = 0;
+        for caps in re.captures_iter(line) {
+            let (tag, auto) = (caps.get(0).unwrap(), caps.get(1).unwrap());
+            let column = line[..tag.start()].chars().count();
+            out.push_str(&line[last..auto.start()]);
+            out.push_str(&column.to_string());
+            last = auto.end();
+        }
+        out.push_str(&line[last..]);
+    }
+    out
+}
+
+// `{{indent 4 text}}` indents the lines after the first, `tab=true` uses tabs and
+// `first=true` also indents the first line, `{{indent auto text}}` indents them to the column
+// of the tag
+soft_helper!(indent_helper: |width: u64, s: str, {tab: bool = false, first: bool = false}| {
+    indent(s, width as usize, tab, first)
+});
+
+// `key:{{nindent 2 text}}` starts a new line and indents every line, unlike `indent` which
+// leaves the first line alone, `tab=true` uses tabs
+soft_helper!(nindent: |width: u64, s: str, {tab: bool = false}| {
+    format!("\n{}", indent(s, width as usize, tab, true))
+});
+
+/// Patterns compiled by `matches`, `None` for invalid ones
+static REGEXES: Mutex<BTreeMap<String, Option<regex::Regex>>> = Mutex::new(BTreeMap::new());
+
+// `{{#if (matches tag "^v\\d+")}}` tests a string against a regex, invalid patterns are false
+soft_helper!(matches: |s: str, pattern: str| {
+    let mut cache = REGEXES.lock().unwrap();
+    if !cache.contains_key(pattern) {
+        let re = regex::Regex::new(pattern);
+        if let Err(e) = &re {
+            soft_fail(format!("matches: invalid pattern {}, {}", pattern, e))?;
+        }
+        cache.insert(pattern.to_owned(), re.ok());
+    }
+    cache[pattern].as_ref().is_some_and(|re| re.is_match(s))
+});
+
+/// `{{setGlobal "version" (concat major "." minor)}}` stores a value for templates rendered
+/// later, templates are rendered in name order so `a.hbs` can set what `b.hbs` reads. Skipped
+/// templates, like up-to-date ones with `--incremental`, don't set anything. Disable with
+/// `--disable-helper setGlobal --disable-helper getGlobal`.
+struct SetGlobal(Arc<HelperState>);
+
+impl HelperDef for SetGlobal {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'reg, 'rc>, RenderError> {
+        let key = h
+            .param(0)
+            .and_then(|p| p.value().as_str())
+            .ok_or_else(|| RenderError::new("setGlobal: expecting a key"))?;
+        let v = h.param(1).map(|p| p.value().clone()).unwrap_or_default();
+        self.0.globals.lock().unwrap().insert(key.to_owned(), v);
+        Ok(ScopedJson::Derived(Value::String(String::new())))
+    }
+}
+
+/// `{{getGlobal "version"}}` reads a value stored by `setGlobal`, `null` if not set
+struct GetGlobal(Arc<HelperState>);
+
+impl HelperDef for GetGlobal {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'reg, 'rc>, RenderError> {
+        let key = h
+            .param(0)
+            .and_then(|p| p.value().as_str())
+            .ok_or_else(|| RenderError::new("getGlobal: expecting a key"))?;
+        let v = self.0.globals.lock().unwrap().get(key).cloned().unwrap_or_default();
+        Ok(ScopedJson::Derived(v))
+    }
+}
+
+/// Read and parse a file relative to the current directory, `null` on failure
+fn load_file<F>(path: &str, parse: F) -> Result<Value, RenderError>
+where
+    F: FnOnce(&str) -> anyhow::Result<Value>,
+{
+    match std::fs::read_to_string(path)
+        .map_err(anyhow::Error::from)
+        .and_then(|s| parse(&s))
+    {
+        Ok(v) => Ok(v),
+        Err(e) => {
+            soft_fail(format!("Cannot load {}, {}", path, e))?;
+            Ok(Value::Null)
+        }
+    }
+}
+
+/// `{{include "sibling.txt"}}` writes a file as is, a relative path is resolved from the
+/// directory of the template being rendered
+struct Include(Arc<HelperState>);
+
+impl HelperDef for Include {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        rc: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'reg, 'rc>, RenderError> {
+        let path = h
+            .param(0)
+            .and_then(|p| p.value().as_str())
+            .ok_or_else(|| RenderError::new("include: expecting a path"))?;
+        let sources = self.0.sources.read().unwrap();
+        let dir = rc
+            .get_current_template_name()
+            .or_else(|| rc.get_root_template_name())
+            .and_then(|name| sources.get(name))
+            .and_then(|source| source.parent());
+        let resolved = match dir {
+            Some(dir) => dir.join(path),
+            None => Path::new(path).to_owned(),
+        };
+        let content = match std::fs::read_to_string(&resolved) {
+            Ok(content) => content,
+            Err(e) => {
+                soft_fail(format!("Cannot include {}, {}", resolved.to_string_lossy(), e))?;
+                String::new()
+            }
+        };
+        Ok(ScopedJson::Derived(Value::String(content)))
+    }
+}
+
+// `{{#with (loadJson "extra.json")}}` parses a JSON file at render time
+soft_helper!(load_json: |path: str| load_file(path, |s| Ok(serde_json::from_str(s)?))?);
+
+// `{{#with (loadYaml "extra.yaml")}}` parses a YAML file at render time
+soft_helper!(load_yaml: |path: str| load_file(path, |s| {
+    let v: serde_yaml::Value = serde_yaml::from_str(s)?;
+    Ok(serde_json::to_value(v)?)
+})?);
+
+// `{{#if (fileExists "override.conf")}}` tests whether a file or directory exists when the
+// template is rendered, a relative path is resolved from the current directory like
+// `loadJson`, not from the template or the output directory
+soft_helper!(file_exists: |path: str| Path::new(path).exists());
+
+/// Helper applying helpers in order to its parameter, declared with `--compose-helper`
+pub struct Composed {
+    /// Expression calling the helpers on the context, like `{{{lowercase (trim this)}}}`
+    expr: String,
+}
+
+impl Composed {
+    /// Compose helpers taking the value as their only parameter, first applied first, `None`
+    /// without any
+    pub fn new(steps: &[&str]) -> Option<Self> {
+        let (last, rest) = steps.split_last()?;
+        let inner = rest
+            .iter()
+            .fold("this".to_owned(), |inner, step| format!("({} {})", step, inner));
+        Some(Self {
+            expr: ["{{{", last, " ", &inner, "}}}"].concat(),
+        })
+    }
+}
+
+impl HelperDef for Composed {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        r: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'reg, 'rc>, RenderError> {
+        let v = h.param(0).map(|p| p.value().clone()).unwrap_or_default();
+        let out = r.render_template(&self.expr, &v)?;
+        Ok(ScopedJson::Derived(Value::String(out)))
+    }
+}
+
+/// Helpers named after Jinja2 filters, mapped to the native helper implementing the same
+/// semantics
+///
+/// | Jinja2    | Native      |
+/// |-----------|-------------|
+/// | `upper`   | `uppercase` |
+/// | `lower`   | `lowercase` |
+/// | `title`   | `titlecase` |
+/// | `trim`    | `trim`      |
+/// | `default` | `default`   |
+/// | `length`  | `length`    |
+/// | `replace` | `replace`   |
+///
+/// The filtered value is always the first parameter, `{{ name | upper }}` is `{{upper name}}`.
+fn jinja_helpers() -> Vec<(&'static str, Box<dyn HelperDef + Send + Sync>)> {
+    vec![
+        ("upper", Box::new(uppercase)),
+        ("lower", Box::new(lowercase)),
+        ("title", Box::new(titlecase)),
+    ]
+}
+
+/// All built-in helpers with their names, with a state of their own
+pub fn builtin_helpers() -> Vec<(&'static str, Box<dyn HelperDef + Send + Sync>)> {
+    builtin_helpers_with(&Arc::default())
+}
+
+/// All built-in helpers with their names, sharing the state of their registry
+pub fn builtin_helpers_with(
+    state: &Arc<HelperState>,
+) -> Vec<(&'static str, Box<dyn HelperDef + Send + Sync>)> {
+    let mut helpers: Vec<(&'static str, Box<dyn HelperDef + Send + Sync>)> = vec![
+        ("gitCommit", Box::new(git_commit)),
+        ("gitBranch", Box::new(git_branch)),
+        ("gitDirty", Box::new(git_dirty)),
+        ("now", Box::new(now_helper)),
+        ("humanBytes", Box::new(human_bytes_helper)),
+        ("parseBytes", Box::new(parse_bytes_helper)),
+        ("numberFormat", Box::new(number_format)),
+        ("uppercase", Box::new(uppercase)),
+        ("lowercase", Box::new(lowercase)),
+        ("titlecase", Box::new(titlecase)),
+        ("trim", Box::new(trim)),
+        ("replace", Box::new(replace)),
+        ("matches", Box::new(matches)),
+        ("default", Box::new(default)),
+        ("length", Box::new(length)),
+        ("toJsonCompact", Box::new(to_json_compact)),
+        ("sortKeys", Box::new(sort_keys_helper)),
+        ("isTruthy", Box::new(is_truthy)),
+        ("assert", Box::new(Assert)),
+        ("commentIf", Box::new(comment_if)),
+        ("at", Box::new(at)),
+        ("sample", Box::new(sample)),
+        ("range", Box::new(range)),
+        ("mergeObjects", Box::new(merge_objects)),
+        ("queryString", Box::new(query_string)),
+        ("urlEncode", Box::new(url_encode)),
+        ("urlDecode", Box::new(url_decode)),
+        ("indent", Box::new(indent_helper)),
+        ("nindent", Box::new(nindent)),
+        ("yamlQuote", Box::new(yaml_quote_helper)),
+        ("yamlKv", Box::new(yaml_kv)),
+        ("include", Box::new(Include(state.clone()))),
+        ("setGlobal", Box::new(SetGlobal(state.clone()))),
+        ("getGlobal", Box::new(GetGlobal(state.clone()))),
+        ("loadJson", Box::new(load_json)),
+        ("loadYaml", Box::new(load_yaml)),
+        ("fileExists", Box::new(file_exists)),
+    ];
+    for (name, feature) in FEATURE_HELPERS {
+        let helper = feature_helper(name, state).unwrap_or_else(|| Box::new(Unavailable(feature)));
+        helpers.push((name, helper));
+    }
+    helpers.extend(jinja_helpers());
+    let scoped = |(name, helper)| -> (&'static str, Box<dyn HelperDef + Send + Sync>) {
+        let state = state.clone();
+        (name, Box::new(Scoped { helper, state }))
+    };
+    helpers.into_iter().map(scoped).collect()
+}
+
+/// Built-in helpers depending on an extra crate, with the Cargo feature enabling them
+const FEATURE_HELPERS: &[(&str, &str)] = &[
+    ("formatDuration", "durations"),
+    ("parseDuration", "durations"),
+    ("pluralize", "inflection"),
+    ("singularize", "inflection"),
+    ("jsonpath", "jsonpath"),
+    ("randomSecret", "random"),
+    ("relPath", "relpath"),
+    ("wrap", "wrap"),
+];
+
+/// The helper of `FEATURE_HELPERS`, `None` if tplgen was built without its feature
+fn feature_helper(
+    name: &str,
+    state: &Arc<HelperState>,
+) -> Option<Box<dyn HelperDef + Send + Sync>> {
+    #[cfg(not(feature = "random"))]
+    let _ = state;
+    match name {
+        #[cfg(feature = "durations")]
+        "formatDuration" => Some(Box::new(format_duration)),
+        #[cfg(feature = "durations")]
+        "parseDuration" => Some(Box::new(parse_duration)),
+        #[cfg(feature = "inflection")]
+        "pluralize" => Some(Box::new(pluralize)),
+        #[cfg(feature = "inflection")]
+        "singularize" => Some(Box::new(singularize)),
+        #[cfg(feature = "jsonpath")]
+        "jsonpath" => Some(Box::new(jsonpath)),
+        #[cfg(feature = "random")]
+        "randomSecret" => Some(Box::new(RandomSecret(state.clone()))),
+        #[cfg(feature = "relpath")]
+        "relPath" => Some(Box::new(rel_path)),
+        #[cfg(feature = "wrap")]
+        "wrap" => Some(Box::new(wrap_helper)),
+        _ => None,
+    }
+}
+
+/// Features of `FEATURE_HELPERS` tplgen was built without
+pub fn missing_features() -> BTreeSet<&'static str> {
+    FEATURE_HELPERS
+        .iter()
+        .filter(|(name, _)| feature_helper(name, &Arc::default()).is_none())
+        .map(|(_, feature)| *feature)
+        .collect()
+}
+
+/// Stand-in of a helper whose feature is disabled, rendering it fails naming the feature
+struct Unavailable(&'static str);
+
+impl HelperDef for Unavailable {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'reg, 'rc>, RenderError> {
+        Err(RenderError::new(format!(
+            "Helper {} not registered, tplgen was built without the `{}` feature",
+            h.name(),
+            self.0
+        )))
+    }
+}
+
+/// The built-in helper with this name
+pub fn builtin_helper(name: &str) -> Option<Box<dyn HelperDef + Send + Sync>> {
+    builtin_helpers()
+        .into_iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, helper)| helper)
+}
+
+/// Register the built-in helpers accepted by `enabled`, sharing the state
+pub fn register_helpers<F>(h: &mut Handlebars, state: &Arc<HelperState>, enabled: F)
+where
+    F: Fn(&str) -> bool,
+{
+    for (name, helper) in builtin_helpers_with(state) {
+        if enabled(name) {
+            h.register_helper(name, helper);
+        } else {
+            debug!("Built-in helper {} disabled", name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn sort_keys_sorts_nested_objects() {
+        let mut inner = serde_json::Map::new();
+        inner.insert("d".to_owned(), json!(1));
+        inner.insert("c".to_owned(), json!(2));
+        let mut outer = serde_json::Map::new();
+        outer.insert("b".to_owned(), json!([Value::Object(inner)]));
+        outer.insert("a".to_owned(), json!(null));
+        let sorted = sort_keys(Value::Object(outer));
+        assert_eq!(sorted.to_string(), r#"{"a":null,"b":[{"c":2,"d":1}]}"#);
+    }
+
+    #[test]
+    fn sort_keys_keeps_scalars() {
+        assert_eq!(sort_keys(json!("x")), json!("x"));
+        assert_eq!(sort_keys(json!([3, 1, 2])), json!([3, 1, 2]));
+    }
+
+    fn registry(strict: bool) -> Handlebars<'static> {
+        let mut h = Handlebars::new();
+        register_helpers(&mut h, &Arc::new(HelperState::new(strict)), |_| true);
+        h
+    }
+
+    #[test]
+    fn registries_have_their_own_globals() {
+        let (a, b) = (registry(false), registry(false));
+        let set = r#"{{setGlobal "k" "v"}}{{getGlobal "k"}}"#;
+        assert_eq!(a.render_template(set, &json!({})).unwrap(), "v");
+        assert_eq!(b.render_template(r#"{{getGlobal "k"}}"#, &json!({})).unwrap(), "");
+    }
+
+    #[test]
+    fn registries_have_their_own_strictness() {
+        let (strict, lenient) = (registry(true), registry(false));
+        assert!(strict.render_template("{{at 1 0}}", &json!({})).is_err());
+        assert_eq!(lenient.render_template("{{at 1 0}}", &json!({})).unwrap(), "");
+    }
+
+    #[test]
+    fn assert_fails_with_strict_mode_only() {
+        let mut h = registry(true);
+        let data = json!({"url": "x", "empty": []});
+        assert_eq!(h.render_template(r#"{{assert url "no url"}}"#, &data).unwrap(), "x");
+        assert_eq!(h.render_template("{{assert empty}}", &data).unwrap(), "");
+        h.set_strict_mode(true);
+        let e = h.render_template(r#"{{assert empty "empty is required"}}"#, &data).unwrap_err();
+        assert!(e.to_string().contains("empty is required"));
+    }
+
+    #[test]
+    fn bad_params_fail_with_strict_helpers_only() {
+        let (strict, lenient) = (registry(true), registry(false));
+        let data = json!({"n": 1});
+        for template in ["A{{uppercase}}B", "A{{uppercase n}}B", "A{{indent \"x\" \"s\"}}B"] {
+            assert_eq!(lenient.render_template(template, &data).unwrap(), "AB", "{}", template);
+            assert!(strict.render_template(template, &data).is_err(), "{}", template);
+        }
+        assert_eq!(lenient.render_template("{{uppercase \"a\"}}", &data).unwrap(), "A");
+    }
+}
@@ -0,0 +1,61 @@
+use anyhow::{bail, Context, Result};
+use std::path::Path;
+
+const FILES: [(&str, &str); 3] = [
+    (
+        "templates/README.md.hbs",
+        r#"# {{titlecase project.name}}
+
+Version {{project.version}}, generated on {{now "%Y-%m-%d"}}.
+
+{{#each features}}
+- {{this}}
+{{/each}}
+
+Generated for {{default USER "you"}}, `USER` comes from the environment.
+"#,
+    ),
+    (
+        "values.yaml",
+        r#"project:
+  name: my project
+  version: 0.1.0
+features:
+  - values are read from values.yaml
+  - environment variables are merged on top
+"#,
+    ),
+    (
+        "tplgen.toml",
+        r#"# Defaults of the command line options, run `tplgen --help` for all of them
+values = "values.yaml"
+output = "out"
+input = ["templates"]
+"#,
+    ),
+];
+
+/// Create a starter project in `dir`, nothing is written if any of the files exists
+pub fn scaffold(dir: &Path) -> Result<()> {
+    for (name, _) in FILES {
+        let path = dir.join(name);
+        if path.exists() {
+            bail!("{} already exists, not overwriting", path.to_string_lossy());
+        }
+    }
+    for (name, content) in FILES {
+        let path = dir.join(name);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&path, content)
+            .with_context(|| format!("Failed to write {}", path.to_string_lossy()))?;
+        println!("Created {}", path.to_string_lossy());
+    }
+    println!();
+    println!("Next steps:");
+    println!("  tplgen            render templates/README.md.hbs into out/README.md");
+    println!("  tplgen --check    render without writing anything");
+    println!("Edit values.yaml and the templates, options default to tplgen.toml.");
+    Ok(())
+}
@@ -0,0 +1,275 @@
+use handlebars::template::{HelperTemplate, Parameter, TemplateElement};
+use handlebars::{Path, Template};
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+/// Data paths referenced by a template, relative to the root context
+///
+/// References inside blocks changing the context to an unknown value, like `each`, are not
+/// included, `with` blocks prefix their references with the block parameter.
+pub fn referenced_paths<F>(t: &Template, is_helper: F) -> Vec<String>
+where
+    F: Fn(&str) -> bool,
+{
+    let mut paths = vec![];
+    walk(t, Some(""), &is_helper, &mut paths);
+    paths.sort();
+    paths.dedup();
+    paths
+}
+
+/// Helpers registered by handlebars itself
+pub const HANDLEBARS_HELPERS: &[&str] = &[
+    "if", "unless", "each", "with", "lookup", "raw", "log", "eq", "ne", "gt", "gte", "lt", "lte",
+    "and", "or", "not", "len",
+];
+
+/// Names of the helpers called by a template, including block helpers and subexpressions
+pub fn referenced_helpers<F>(t: &Template, is_helper: F) -> Vec<String>
+where
+    F: Fn(&str) -> bool,
+{
+    let mut names = BTreeSet::new();
+    walk_helpers(t, &is_helper, &mut names);
+    names.into_iter().collect()
+}
+
+/// Look up a dotted path in the data
+pub fn lookup<'a>(data: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.')
+        .filter(|s| !s.is_empty())
+        .try_fold(data, |v, seg| match v {
+            Value::Object(m) => m.get(seg),
+            Value::Array(a) => seg.parse::<usize>().ok().and_then(|i| a.get(i)),
+            _ => None,
+        })
+}
+
+/// Names of the partials included by a template, dynamic partial names are not included
+pub fn included_partials(t: &Template) -> BTreeSet<String> {
+    let mut names = BTreeSet::new();
+    walk_partials(t, &mut names);
+    names
+}
+
+/// Loops of templates including each other, each as the names along the loop
+pub fn include_cycles(templates: &HashMap<String, Template>) -> Vec<Vec<String>> {
+    let graph: BTreeMap<&str, BTreeSet<String>> = templates
+        .iter()
+        .map(|(name, t)| (name.as_str(), included_partials(t)))
+        .collect();
+    let mut done = BTreeSet::new();
+    let mut cycles = vec![];
+    for name in graph.keys() {
+        let mut stack = vec![];
+        visit(name, &graph, &mut stack, &mut done, &mut cycles);
+    }
+    cycles
+}
+
+fn visit<'a>(
+    name: &'a str,
+    graph: &'a BTreeMap<&str, BTreeSet<String>>,
+    stack: &mut Vec<&'a str>,
+    done: &mut BTreeSet<&'a str>,
+    cycles: &mut Vec<Vec<String>>,
+) {
+    if let Some(pos) = stack.iter().position(|n| *n == name) {
+        let mut cycle: Vec<String> = stack[pos..].iter().map(|n| n.to_string()).collect();
+        cycle.push(name.to_owned());
+        cycles.push(cycle);
+        return;
+    }
+    if done.contains(name) {
+        return;
+    }
+    stack.push(name);
+    for next in graph.get(name).into_iter().flatten() {
+        visit(next, graph, stack, done, cycles);
+    }
+    stack.pop();
+    done.insert(name);
+}
+
+fn walk_helper_params<F>(params: &[Parameter], is_helper: &F, out: &mut BTreeSet<String>)
+where
+    F: Fn(&str) -> bool,
+{
+    for p in params {
+        if let Parameter::Subexpression(sub) = p {
+            if sub.is_helper() || is_helper(sub.name()) {
+                out.insert(sub.name().to_owned());
+            }
+            walk_helper_params(sub.params().map(|v| &v[..]).unwrap_or_default(), is_helper, out);
+            if let Some(hash) = sub.hash() {
+                walk_helper_params(&hash.values().cloned().collect::<Vec<_>>(), is_helper, out);
+            }
+        }
+    }
+}
+
+fn walk_helpers<F>(t: &Template, is_helper: &F, out: &mut BTreeSet<String>)
+where
+    F: Fn(&str) -> bool,
+{
+    for e in &t.elements {
+        match e {
+            TemplateElement::Expression(ht)
+            | TemplateElement::HtmlExpression(ht)
+            | TemplateElement::HelperBlock(ht) => {
+                let name = ht.name.as_name().unwrap_or_default();
+                let called = ht.block || !ht.params.is_empty() || !ht.hash.is_empty();
+                if !name.is_empty() && (called || is_helper(name)) {
+                    out.insert(name.to_owned());
+                }
+                walk_helper_params(&ht.params, is_helper, out);
+                walk_helper_params(&ht.hash.values().cloned().collect::<Vec<_>>(), is_helper, out);
+                for t in ht.template.iter().chain(&ht.inverse) {
+                    walk_helpers(t, is_helper, out);
+                }
+            }
+            TemplateElement::PartialExpression(dt)
+            | TemplateElement::PartialBlock(dt)
+            | TemplateElement::DecoratorBlock(dt) => {
+                walk_helper_params(&dt.params, is_helper, out);
+                if let Some(t) = &dt.template {
+                    walk_helpers(t, is_helper, out);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn walk_partials(t: &Template, out: &mut BTreeSet<String>) {
+    for e in &t.elements {
+        match e {
+            TemplateElement::HelperBlock(ht) => {
+                for t in ht.template.iter().chain(&ht.inverse) {
+                    walk_partials(t, out);
+                }
+            }
+            TemplateElement::DecoratorBlock(dt) => {
+                if let Some(t) = &dt.template {
+                    walk_partials(t, out);
+                }
+            }
+            TemplateElement::PartialExpression(dt) | TemplateElement::PartialBlock(dt) => {
+                if let Some(name) = dt.name.as_name().filter(|n| !n.starts_with('@')) {
+                    out.insert(name.to_owned());
+                }
+                if let Some(t) = &dt.template {
+                    walk_partials(t, out);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Convert a handlebars path into a dotted path, `None` for local variables and parent access
+fn normalize(raw: &str) -> Option<String> {
+    if raw.starts_with('@') || raw.starts_with("..") {
+        return None;
+    }
+    let segs: Vec<&str> = raw
+        .split(['.', '/'])
+        .map(|s| s.trim_start_matches('[').trim_end_matches(']'))
+        .filter(|s| !s.is_empty() && *s != "this")
+        .collect();
+    Some(segs.join("."))
+}
+
+fn param_path(p: &Parameter) -> Option<String> {
+    match p {
+        Parameter::Path(Path::Relative((_, raw))) => normalize(raw),
+        Parameter::Name(name) => normalize(name),
+        _ => None,
+    }
+}
+
+fn join(scope: &str, path: &str) -> String {
+    match (scope.is_empty(), path.is_empty()) {
+        (true, _) => path.to_owned(),
+        (false, true) => scope.to_owned(),
+        (false, false) => format!("{}.{}", scope, path),
+    }
+}
+
+fn walk_params<F>(params: &[Parameter], scope: Option<&str>, is_helper: &F, out: &mut Vec<String>)
+where
+    F: Fn(&str) -> bool,
+{
+    for p in params {
+        match p {
+            Parameter::Subexpression(sub) => {
+                walk_params(sub.params().map(|v| &v[..]).unwrap_or_default(), scope, is_helper, out);
+                if let Some(hash) = sub.hash() {
+                    walk_params(&hash.values().cloned().collect::<Vec<_>>(), scope, is_helper, out);
+                }
+                if !sub.is_helper() && !is_helper(sub.name()) {
+                    if let (Some(scope), Some(path)) = (scope, normalize(sub.name())) {
+                        out.push(join(scope, &path));
+                    }
+                }
+            }
+            _ => {
+                if let (Some(scope), Some(path)) = (scope, param_path(p)) {
+                    if !path.is_empty() {
+                        out.push(join(scope, &path));
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn walk_helper<F>(ht: &HelperTemplate, scope: Option<&str>, is_helper: &F, out: &mut Vec<String>)
+where
+    F: Fn(&str) -> bool,
+{
+    let name = ht.name.as_name().unwrap_or_default();
+    if ht.params.is_empty() && ht.hash.is_empty() && !ht.block && !is_helper(name) {
+        if let (Some(scope), Some(path)) = (scope, param_path(&ht.name)) {
+            out.push(join(scope, &path));
+        }
+        return;
+    }
+    walk_params(&ht.params, scope, is_helper, out);
+    walk_params(&ht.hash.values().cloned().collect::<Vec<_>>(), scope, is_helper, out);
+
+    let inner = match name {
+        "with" => match (scope, ht.params.first().and_then(param_path)) {
+            (Some(scope), Some(path)) => Some(join(scope, &path)),
+            _ => None,
+        },
+        "each" => None,
+        _ => scope.map(str::to_owned),
+    };
+    if let Some(t) = &ht.template {
+        walk(t, inner.as_deref(), is_helper, out);
+    }
+    if let Some(t) = &ht.inverse {
+        walk(t, scope, is_helper, out);
+    }
+}
+
+fn walk<F>(t: &Template, scope: Option<&str>, is_helper: &F, out: &mut Vec<String>)
+where
+    F: Fn(&str) -> bool,
+{
+    for e in &t.elements {
+        match e {
+            TemplateElement::Expression(ht)
+            | TemplateElement::HtmlExpression(ht)
+            | TemplateElement::HelperBlock(ht) => walk_helper(ht, scope, is_helper, out),
+            TemplateElement::PartialExpression(dt) | TemplateElement::PartialBlock(dt) => {
+                walk_params(&dt.params, scope, is_helper, out);
+                if let Some(t) = &dt.template {
+                    walk(t, scope, is_helper, out);
+                }
+            }
+            _ => {}
+        }
+    }
+}
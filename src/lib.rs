@@ -0,0 +1,4581 @@
+use anyhow::{Context, Result};
+use handlebars::{
+    Handlebars, HelperDef, Output, RenderContext, RenderError, Renderable, StringOutput, Template,
+};
+use indicatif::{ProgressBar, ProgressStyle};
+use rayon::prelude::*;
+use regex::Regex;
+use log::{debug, error, info, warn, LevelFilter};
+use serde::{Deserialize, Serialize};
+use serde_json::{from_reader, to_value, Value};
+use walkdir::{WalkDir, DirEntry};
+use std::{collections::{BTreeMap, BTreeSet, HashMap, HashSet}, sync::{Arc, Mutex}, fs::File, io::{BufReader, IsTerminal, Read, Write}, path::{PathBuf, Path, self}, time::SystemTime};
+use structopt::StructOpt;
+
+use front_matter::FrontMatter;
+
+mod archive;
+mod diagnostic;
+mod embedded;
+mod front_matter;
+mod helpers;
+mod init;
+mod inspect;
+#[cfg(feature = "wasm")]
+mod wasm;
+
+pub use helpers::{builtin_helper, builtin_helpers};
+pub use init::scaffold;
+
+/// Log if `Result` is an error
+pub trait Logged {
+    fn log(self) -> Self;
+}
+
+impl<T, E> Logged for Result<T, E>
+where
+    E: std::fmt::Display,
+{
+    fn log(self) -> Self {
+        if let Err(e) = &self {
+            warn!("{}", e)
+        }
+        self
+    }
+}
+
+/// Recursively merge `b` into `a`, objects are merged key by key, anything else in `b` replaces `a`
+///
+/// A `+key` in `b` appends to the string or array under `key` in `a` instead of replacing it.
+fn merge(a: &mut Value, b: Value) {
+    match (a, b) {
+        (Value::Object(a), Value::Object(b)) => {
+            for (k, v) in b {
+                match k.strip_prefix('+') {
+                    Some(key) if !key.is_empty() => {
+                        match (a.entry(key).or_insert(Value::Null), v) {
+                            (Value::String(s), Value::String(v)) => s.push_str(&v),
+                            (Value::Array(items), Value::Array(v)) => items.extend(v),
+                            (existing, v) => merge(existing, v),
+                        }
+                    }
+                    _ => merge(a.entry(k).or_insert(Value::Null), v),
+                }
+            }
+        }
+        (a, b) => *a = b,
+    }
+}
+
+/// Record `source` as the provenance of each leaf of `v`, leaves are scalars and arrays
+fn trace_leaves(trace: &mut BTreeMap<String, String>, prefix: &str, v: &Value, source: &str) {
+    match v {
+        Value::Object(m) if !m.is_empty() => {
+            for (k, v) in m {
+                let path = if prefix.is_empty() {
+                    k.to_owned()
+                } else {
+                    format!("{}.{}", prefix, k)
+                };
+                trace_leaves(trace, &path, v, source);
+            }
+        }
+        _ => {
+            trace.insert(prefix.to_owned(), source.to_owned());
+        }
+    }
+}
+
+/// JSON pointer and dotted path of each string leaf of `v` containing a handlebars expression
+fn template_leaves(v: &Value, pointer: &str, path: &str, out: &mut Vec<(String, String)>) {
+    let child = |k: &str| {
+        let pointer = format!("{}/{}", pointer, k.replace('~', "~0").replace('/', "~1"));
+        let path = if path.is_empty() {
+            k.to_owned()
+        } else {
+            format!("{}.{}", path, k)
+        };
+        (pointer, path)
+    };
+    match v {
+        Value::String(s) if s.contains("{{") => out.push((pointer.to_owned(), path.to_owned())),
+        Value::Object(m) => {
+            for (k, v) in m {
+                let (pointer, path) = child(k);
+                template_leaves(v, &pointer, &path, out);
+            }
+        }
+        Value::Array(a) => {
+            for (i, v) in a.iter().enumerate() {
+                let (pointer, path) = child(&i.to_string());
+                template_leaves(v, &pointer, &path, out);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Render the string values containing handlebars expressions against the merged values with
+/// `--resolve-refs`, until nothing changes so values can reference values that reference
+/// others. Values referencing missing keys or in a reference cycle are kept as they were.
+fn resolve_refs(data: &mut Value, opt: &Opt) {
+    let mut leaves = vec![];
+    template_leaves(data, "", "", &mut leaves);
+    if leaves.is_empty() {
+        return;
+    }
+    let mut engine = Handlebars::new();
+    engine.set_strict_mode(true);
+    engine.register_escape_fn(handlebars::no_escape);
+    if !opt.no_builtin_helpers {
+        let disabled = &opt.disable_helper;
+        let state = Arc::new(helpers::HelperState::new(opt.strict_helpers));
+        helpers::register_helpers(&mut engine, &state, |name| !disabled.iter().any(|n| n == name));
+    }
+    let original = data.clone();
+    let mut unresolved = BTreeMap::new();
+    // a cycle like `a: "x{{a}}"` keeps growing, each pass resolves at least one level
+    for _ in 0..=leaves.len() {
+        let snapshot = data.clone();
+        let mut changed = false;
+        for (pointer, path) in &leaves {
+            let current = match snapshot.pointer(pointer) {
+                Some(Value::String(s)) if s.contains("{{") && !unresolved.contains_key(path) => s,
+                _ => continue,
+            };
+            match engine.render_template(current, &snapshot) {
+                Ok(rendered) if rendered != *current => {
+                    if let Some(v) = data.pointer_mut(pointer) {
+                        *v = Value::String(rendered);
+                        changed = true;
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    unresolved.insert(path.clone(), e.desc);
+                }
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+    let mut cycles = vec![];
+    for (pointer, path) in &leaves {
+        let pending = matches!(data.pointer(pointer), Some(Value::String(s)) if s.contains("{{"));
+        if pending || unresolved.contains_key(path) {
+            if let (Some(v), Some(o)) = (data.pointer_mut(pointer), original.pointer(pointer)) {
+                *v = o.clone();
+            }
+        }
+        if pending && !unresolved.contains_key(path) {
+            cycles.push(path.as_str());
+        }
+    }
+    for (path, e) in &unresolved {
+        warn!("Cannot resolve the references of value {}, {}", path, e);
+    }
+    if !cycles.is_empty() {
+        warn!("Values referencing each other in a cycle: {}", cycles.join(", "));
+    }
+}
+
+/// Remove the keys matching the dotted path, each segment can use `*` and `?` wildcards
+fn omit_key(v: &mut Value, segments: &[Regex], prefix: &str) {
+    let (first, rest) = match segments.split_first() {
+        Some(s) => s,
+        None => return,
+    };
+    if let Value::Object(m) = v {
+        let keys: Vec<String> = m.keys().filter(|k| first.is_match(k)).cloned().collect();
+        for k in keys {
+            let path = format!("{}{}", prefix, k);
+            if rest.is_empty() {
+                debug!("Omitting {}", path);
+                m.remove(&k);
+            } else if let Some(child) = m.get_mut(&k) {
+                omit_key(child, rest, &format!("{}.", path));
+            }
+        }
+    }
+}
+
+/// Convert a glob segment into an anchored regex
+fn glob_regex(glob: &str) -> Option<Regex> {
+    let pattern = regex::escape(glob).replace("\\*", ".*").replace("\\?", ".");
+    Regex::new(&format!("^{}$", pattern)).log().ok()
+}
+
+/// Check that `text` parses as JSON, YAML or TOML, the error includes the position
+fn validate(text: &str, format: &str) -> Result<(), String> {
+    match format {
+        "json" => serde_json::from_str::<serde::de::IgnoredAny>(text)
+            .map(drop)
+            .map_err(|e| e.to_string()),
+        "toml" => toml::from_str::<toml::Value>(text)
+            .map(drop)
+            .map_err(|e| e.to_string()),
+        _ => serde_yaml::Deserializer::from_str(text)
+            .try_for_each(|doc| serde_yaml::Value::deserialize(doc).map(drop))
+            .map_err(|e| e.to_string()),
+    }
+}
+
+/// Parse `text` as YAML or JSON and serialize it again with sorted keys, `None` if it doesn't
+/// parse
+fn reformat(text: &str, format: &str) -> Option<String> {
+    if format == "json" {
+        let v = helpers::sort_keys(serde_json::from_str(text).log().ok()?);
+        serde_json::to_string_pretty(&v).ok().map(|s| s + "\n")
+    } else {
+        let v: serde_yaml::Value = serde_yaml::from_str(text).log().ok()?;
+        let v = helpers::sort_keys(to_value(v).log().ok()?);
+        let s = serde_yaml::to_string(&v).ok()?;
+        Some(s.strip_prefix("---\n").unwrap_or(&s).to_owned())
+    }
+}
+
+/// Flatten nested objects and arrays into a map with dotted keys, `{"a": {"b": [1]}}` becomes
+/// `{"a.b.0": 1}`
+fn flatten(v: &Value, prefix: &str, out: &mut serde_json::Map<String, Value>) {
+    let join = |k: &str| {
+        if prefix.is_empty() {
+            k.to_owned()
+        } else {
+            format!("{}.{}", prefix, k)
+        }
+    };
+    match v {
+        Value::Object(m) if !m.is_empty() => {
+            for (k, v) in m {
+                flatten(v, &join(k), out);
+            }
+        }
+        Value::Array(a) if !a.is_empty() => {
+            for (i, v) in a.iter().enumerate() {
+                flatten(v, &join(&i.to_string()), out);
+            }
+        }
+        v => {
+            out.insert(prefix.to_owned(), v.clone());
+        }
+    }
+}
+
+/// Expand the YAML merge keys `<<: *anchor` and `<<: [*a, *b]` recursively, the keys of the
+/// mapping win over the merged ones and earlier merged mappings win over later ones
+fn expand_merge_keys(v: serde_yaml::Value) -> serde_yaml::Value {
+    use serde_yaml::Value as Yaml;
+    match v {
+        Yaml::Mapping(m) => {
+            let merge_key = Yaml::String("<<".to_owned());
+            let mut out = serde_yaml::Mapping::new();
+            let mut sources = vec![];
+            for (k, v) in m {
+                let v = expand_merge_keys(v);
+                match v {
+                    Yaml::Sequence(s) if k == merge_key => sources.extend(s),
+                    v if k == merge_key => sources.push(v),
+                    v => {
+                        out.insert(k, v);
+                    }
+                }
+            }
+            for source in sources {
+                match source {
+                    Yaml::Mapping(source) => {
+                        for (k, v) in source {
+                            if !out.contains_key(&k) {
+                                out.insert(k, v);
+                            }
+                        }
+                    }
+                    _ => warn!("Ignoring a YAML merge key `<<` whose value is not a map"),
+                }
+            }
+            Yaml::Mapping(out)
+        }
+        Yaml::Sequence(s) => Yaml::Sequence(s.into_iter().map(expand_merge_keys).collect()),
+        v => v,
+    }
+}
+
+/// Escape a key or a value of a Java properties file like `Properties.store`, characters
+/// outside printable ASCII become `\uXXXX`
+fn escape_property(s: &str, key: bool) -> String {
+    let mut out = String::with_capacity(s.len());
+    for (i, c) in s.chars().enumerate() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '\x0c' => out.push_str("\\f"),
+            ' ' if key || i == 0 => out.push_str("\\ "),
+            '=' | ':' | '#' | '!' => {
+                out.push('\\');
+                out.push(c);
+            }
+            ' '..='~' => out.push(c),
+            _ => {
+                let mut units = [0; 2];
+                for unit in c.encode_utf16(&mut units) {
+                    out.push_str(&format!("\\u{:04X}", unit));
+                }
+            }
+        }
+    }
+    out
+}
+
+/// Flatten the data into sorted `dotted.key=value` lines of a Java properties file
+fn to_properties(data: &Value) -> String {
+    let mut flat = serde_json::Map::new();
+    flatten(data, "", &mut flat);
+    let mut out = String::new();
+    for (k, v) in &flat {
+        let v = match v {
+            Value::String(s) => s.clone(),
+            Value::Null => String::new(),
+            v => v.to_string(),
+        };
+        out.push_str(&format!("{}={}\n", escape_property(k, true), escape_property(&v, false)));
+    }
+    out
+}
+
+/// Turn object keys like `server.port` into nested objects recursively, a value and an object
+/// under the same key are reported and the object is kept
+fn expand_dotted_keys(v: Value) -> Value {
+    fn put(m: &mut serde_json::Map<String, Value>, k: String, v: Value, path: &str) {
+        let path = if path.is_empty() { k.clone() } else { format!("{}.{}", path, k) };
+        match (m.get_mut(&k), v) {
+            (None, v) => {
+                m.insert(k, v);
+            }
+            (Some(Value::Object(existing)), Value::Object(v)) => {
+                for (k, v) in v {
+                    put(existing, k, v, &path);
+                }
+            }
+            (Some(Value::Object(_)), _) => {
+                warn!("Key {} is both a value and an object, keeping the object", path)
+            }
+            (Some(existing), v @ Value::Object(_)) => {
+                warn!("Key {} is both a value and an object, keeping the object", path);
+                *existing = v;
+            }
+            (Some(existing), v) => *existing = v,
+        }
+    }
+    match v {
+        Value::Object(m) => {
+            let mut out = serde_json::Map::new();
+            for (k, v) in m {
+                let v = expand_dotted_keys(v);
+                let segments: Vec<&str> = k.split('.').collect();
+                if segments.len() > 1 && segments.iter().all(|s| !s.is_empty()) {
+                    let nested = segments[1..].iter().rev().fold(v, |v, seg| {
+                        let mut m = serde_json::Map::new();
+                        m.insert(seg.to_string(), v);
+                        Value::Object(m)
+                    });
+                    put(&mut out, segments[0].to_owned(), nested, "");
+                } else {
+                    put(&mut out, k, v, "");
+                }
+            }
+            Value::Object(out)
+        }
+        Value::Array(a) => Value::Array(a.into_iter().map(expand_dotted_keys).collect()),
+        v => v,
+    }
+}
+
+/// Lowercase all object keys recursively, values of keys differing only by case are merged
+/// in order
+fn lowercase_keys(v: Value) -> Value {
+    match v {
+        Value::Object(m) => {
+            let mut out = Value::Object(Default::default());
+            for (k, v) in m {
+                let mut entry = serde_json::Map::new();
+                entry.insert(k.to_lowercase(), lowercase_keys(v));
+                merge(&mut out, Value::Object(entry));
+            }
+            out
+        }
+        Value::Array(a) => Value::Array(a.into_iter().map(lowercase_keys).collect()),
+        v => v,
+    }
+}
+
+/// Apply `patch` to `target` with JSON Merge Patch semantics (RFC 7386), `null` removes a key
+fn merge_patch(target: &mut Value, patch: Value) {
+    match patch {
+        Value::Object(patch) => {
+            if !target.is_object() {
+                *target = Value::Object(Default::default());
+            }
+            if let Value::Object(map) = target {
+                for (k, v) in patch {
+                    if v.is_null() {
+                        map.remove(&k);
+                    } else {
+                        merge_patch(map.entry(k).or_insert(Value::Null), v);
+                    }
+                }
+            }
+        }
+        patch => *target = patch,
+    }
+}
+
+/// How values from several sources are combined
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MergeMode {
+    /// Recursive merge, `null` replaces the value
+    Deep,
+    /// JSON Merge Patch (RFC 7386), `null` removes the key
+    Jsonmerge,
+}
+
+impl MergeMode {
+    fn merge(self, a: &mut Value, b: Value) {
+        match self {
+            Self::Deep => merge(a, b),
+            Self::Jsonmerge => merge_patch(a, b),
+        }
+    }
+}
+
+impl std::str::FromStr for MergeMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "deep" => Ok(Self::Deep),
+            "jsonmerge" => Ok(Self::Jsonmerge),
+            _ => Err(format!("Unknown merge mode {}", s)),
+        }
+    }
+}
+
+/// Replace the lines between the line containing `start` and the next line containing `end`
+/// with `content`, `None` if the markers are not found
+fn splice_region(existing: &str, content: &str, start: &str, end: &str) -> Option<String> {
+    let mut offset = 0;
+    let mut begin = None;
+    for line in existing.split_inclusive('\n') {
+        match begin {
+            None if line.contains(start) => begin = Some(offset + line.len()),
+            Some(begin) if line.contains(end) => {
+                let mut out = existing[..begin].to_owned();
+                if !out.is_empty() && !out.ends_with('\n') {
+                    out.push('\n');
+                }
+                out.push_str(content);
+                if !content.is_empty() && !content.ends_with('\n') {
+                    out.push('\n');
+                }
+                out.push_str(&existing[offset..]);
+                return Some(out);
+            }
+            _ => {}
+        }
+        offset += line.len();
+    }
+    None
+}
+
+/// Read the `KEY=value` lines of a dotenv file, skipping comments, `export` prefixes and
+/// surrounding quotes
+fn read_dotenv(path: &Path) -> Option<Vec<(String, String)>> {
+    let content = std::fs::read_to_string(path).ok()?;
+    debug!("Loading environment variables from {}", path.to_string_lossy());
+    let vars = content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let line = line.strip_prefix("export ").unwrap_or(line);
+            let (k, v) = match line.split_once('=') {
+                Some(kv) => kv,
+                None => {
+                    warn!("Invalid line in {}: {}", path.to_string_lossy(), line);
+                    return None;
+                }
+            };
+            let v = v.trim();
+            let v = if let Some(v) = v.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+                v.replace("\\n", "\n").replace("\\\"", "\"")
+            } else if let Some(v) = v.strip_prefix('\'').and_then(|v| v.strip_suffix('\'')) {
+                v.to_owned()
+            } else {
+                v.split(" #").next().unwrap_or_default().trim_end().to_owned()
+            };
+            Some((k.trim().to_owned(), v))
+        })
+        .collect();
+    Some(vars)
+}
+
+/// Strip the suffix, ignoring ASCII case if asked, so the stripped part keeps its length
+fn strip_suffix<'a>(s: &'a str, suffix: &str, ignore_case: bool) -> Option<&'a str> {
+    let split = s.len().checked_sub(suffix.len())?;
+    let (head, tail) = (s.get(..split)?, s.get(split..)?);
+    let matched = if ignore_case {
+        tail.eq_ignore_ascii_case(suffix)
+    } else {
+        tail == suffix
+    };
+    matched.then_some(head)
+}
+
+/// Split the text into files at the lines matching the marker, where `{path}` in the marker
+/// stands for the path of the next file, like `# ---FILE: {path}---`, `None` without markers
+fn split_files<'a>(text: &'a str, marker: &str) -> Option<Vec<(&'a str, &'a str)>> {
+    let (prefix, suffix) = marker.split_once("{path}").unwrap_or((marker, ""));
+    let mut files = vec![];
+    let mut current: Option<(&str, usize)> = None;
+    let mut offset = 0;
+    for line in text.split_inclusive('\n') {
+        let path = line
+            .trim_end()
+            .strip_prefix(prefix)
+            .and_then(|rest| rest.strip_suffix(suffix))
+            .map(str::trim);
+        if let Some(path) = path.filter(|p| !p.is_empty()) {
+            match current {
+                Some((prev, start)) => files.push((prev, &text[start..offset])),
+                None if !text[..offset].trim().is_empty() => {
+                    warn!("Ignoring the output before the first split marker");
+                }
+                None => {}
+            }
+            current = Some((path, offset + line.len()));
+        }
+        offset += line.len();
+    }
+    let (path, start) = current?;
+    files.push((path, &text[start..]));
+    Some(files)
+}
+
+/// Top-level keys of `v` missing in `known`, described with their source
+fn unknown_keys(known: &BTreeSet<String>, v: &Value, source: &str) -> Vec<String> {
+    v.as_object()
+        .into_iter()
+        .flat_map(|m| m.keys())
+        .filter(|k| {
+            let k = k.strip_prefix('+').filter(|k| !k.is_empty()).unwrap_or(k);
+            !known.contains(k)
+        })
+        .map(|k| format!("{} (from {})", k, source))
+        .collect()
+}
+
+/// Template of the `--index` without `--index-template`, links are relative to the output
+/// directory
+const DEFAULT_INDEX: &str = "# Index
+
+{{#each files}}
+- [{{path}}]({{path}}) ({{humanBytes size}})
+{{/each}}
+";
+
+/// Parse octal permissions like `0600` or `755`
+fn parse_mode(s: &str) -> Option<u32> {
+    u32::from_str_radix(s.trim().trim_start_matches("0o"), 8)
+        .ok()
+        .filter(|m| *m <= 0o7777)
+}
+
+/// Apply symbolic permissions like `u+x` or `go-w,o=r` to a mode, as `chmod` does but without
+/// the umask, clauses are separated by commas
+fn apply_symbolic_mode(mode: u32, spec: &str) -> Option<u32> {
+    let mut mode = mode;
+    for clause in spec.trim().split(',') {
+        let ops = clause.trim_start_matches(|c| "ugoa".contains(c));
+        let who = clause[..clause.len() - ops.len()].chars().fold(0, |who, c| {
+            who | match c {
+                'u' => 0o4700,
+                'g' => 0o2070,
+                'o' => 0o1007,
+                _ => 0o7777,
+            }
+        });
+        let who = if who == 0 { 0o7777 } else { who };
+        let mut rest = ops;
+        if rest.is_empty() {
+            return None;
+        }
+        while let Some(op) = rest.chars().next().filter(|c| "+-=".contains(*c)) {
+            let perms = rest[1..].trim_start_matches(|c| "rwxXst".contains(c));
+            let bits = rest[1..rest.len() - perms.len()].chars().fold(0, |bits, c| {
+                bits | match c {
+                    'r' => 0o444,
+                    'w' => 0o222,
+                    'x' => 0o111,
+                    'X' if mode & 0o111 != 0 => 0o111,
+                    'X' => 0,
+                    's' => 0o6000,
+                    _ => 0o1000,
+                }
+            }) & who;
+            mode = match op {
+                '+' => mode | bits,
+                '-' => mode & !bits,
+                _ => mode & !who | bits,
+            };
+            rest = perms;
+        }
+        if !rest.is_empty() {
+            return None;
+        }
+    }
+    Some(mode)
+}
+
+/// Move a file, by copying it and removing the original when renaming fails, like across
+/// filesystems
+fn move_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    if std::fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    std::fs::copy(from, to)?;
+    if let Ok(meta) = std::fs::metadata(from) {
+        let mtime = filetime::FileTime::from_last_modification_time(&meta);
+        filetime::set_file_mtime(to, mtime).ok();
+    }
+    std::fs::remove_file(from)
+}
+
+/// Current permissions of a file
+fn file_mode(path: &Path) -> Option<u32> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::metadata(path).ok().map(|m| m.permissions().mode() & 0o7777)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+        None
+    }
+}
+
+/// Split a leading `#!` line, with its line break, from the rest of the text
+fn split_shebang(text: &str) -> (&str, &str) {
+    if !text.starts_with("#!") {
+        return ("", text);
+    }
+    match text.find('\n') {
+        Some(i) => text.split_at(i + 1),
+        None => (text, ""),
+    }
+}
+
+/// Lowercase hex digest of the content with `sha256` or `sha512`
+fn hex_digest(algo: &str, content: &[u8]) -> String {
+    use sha2::Digest;
+    let digest = match algo {
+        "sha512" => sha2::Sha512::digest(content).to_vec(),
+        _ => sha2::Sha256::digest(content).to_vec(),
+    };
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Tag a generated file with the `user.tplgen.template` and `user.tplgen.sha256` extended
+/// attributes, a no-op outside Unix
+fn set_xattrs(path: &Path, name: &str, content: &[u8]) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        xattr::set(path, "user.tplgen.template", name.as_bytes())?;
+        xattr::set(path, "user.tplgen.sha256", hex_digest("sha256", content).as_bytes())
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (name, content);
+        warn!("Not tagging {}, extended attributes are only on Unix", path.to_string_lossy());
+        Ok(())
+    }
+}
+
+/// Set the permissions of a file, a no-op outside Unix
+fn set_mode(path: &Path, mode: u32) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+    }
+    #[cfg(not(unix))]
+    {
+        warn!("Ignoring mode {:o} of {}, only supported on Unix", mode, path.to_string_lossy());
+        Ok(())
+    }
+}
+
+fn mtime(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Handlebars truthiness, `false`, `null`, `0`, empty strings and empty arrays are falsy
+fn is_truthy(v: &Value) -> bool {
+    match v {
+        Value::Null => false,
+        Value::Bool(b) => *b,
+        Value::Number(n) => n.as_f64() != Some(0.0),
+        Value::String(s) => !s.is_empty(),
+        Value::Array(a) => !a.is_empty(),
+        Value::Object(_) => true,
+    }
+}
+
+/// Resolve symlinks of an existing path like git does, or only normalize a deleted one
+fn canonical_path(path: &Path) -> PathBuf {
+    std::fs::canonicalize(path).unwrap_or_else(|_| normalize_path(path))
+}
+
+/// Make a path absolute and resolve `.` and `..` without touching the filesystem
+fn normalize_path(path: &Path) -> PathBuf {
+    let path = std::env::current_dir().unwrap_or_default().join(path);
+    let mut out = PathBuf::new();
+    for c in path.components() {
+        match c {
+            path::Component::CurDir => {}
+            path::Component::ParentDir => {
+                out.pop();
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Parse strings like `true`, `false`, `null` and numbers into typed values, anything else stays a string
+fn coerce_scalar(s: &str) -> Value {
+    match s.to_ascii_lowercase().as_str() {
+        "true" => Value::Bool(true),
+        "false" => Value::Bool(false),
+        "null" => Value::Null,
+        _ => match serde_json::from_str::<serde_json::Number>(s) {
+            Ok(n) => Value::Number(n),
+            Err(_) => Value::String(s.to_owned()),
+        },
+    }
+}
+
+/// Name of the partial of a template file under a `_partials` directory, without it, or
+/// starting with `_`, without the underscore, `None` for other files
+///
+/// `_partials/common/meta` is included as `{{> common/meta}}` and `mail/_footer` as
+/// `{{> mail/footer}}`.
+fn underscore_partial(dirs: &str, file: &str) -> Option<String> {
+    let in_partials = dirs.split('/').any(|d| d == "_partials");
+    let bare = file.strip_prefix('_').filter(|f| !f.is_empty());
+    if !in_partials && bare.is_none() {
+        return None;
+    }
+    let file = if in_partials { file } else { bare.unwrap_or(file) };
+    let name = dirs
+        .split('/')
+        .filter(|d| !d.is_empty() && *d != "_partials")
+        .chain(std::iter::once(file))
+        .collect::<Vec<_>>()
+        .join("/");
+    Some(name)
+}
+
+/// Keys of the value of an environment variable, its name split at the `--env-separator` into
+/// lowercase keys, names without the separator stay flat as they are
+fn env_keys(name: &str, sep: Option<&str>) -> Vec<String> {
+    match sep {
+        Some(sep) if name.contains(sep) => name.split(sep).map(str::to_lowercase).collect(),
+        _ => vec![name.to_owned()],
+    }
+}
+
+/// The value of an environment variable nested under its keys, to merge into the values, an
+/// object on the way replaces a value and a value doesn't replace an object, so values more
+/// nested than others win
+fn nested_env_value(map: &Value, keys: &[String], v: Value, var: &str) -> Option<Value> {
+    let mut current = map;
+    for (i, key) in keys.iter().enumerate() {
+        let last = i + 1 == keys.len();
+        match current.get(key) {
+            Some(Value::Object(_)) if last && !v.is_object() => {
+                warn!("Environment variable {} would replace an object, ignored", var);
+                return None;
+            }
+            Some(Value::Object(_)) => current = &current[key],
+            Some(_) if !last => {
+                warn!("Environment variable {} replaces the value of {}", var, key);
+                break;
+            }
+            _ => break,
+        }
+    }
+    Some(keys.iter().rev().fold(v, |v, key| {
+        let mut m = serde_json::Map::default();
+        m.insert(key.clone(), v);
+        Value::Object(m)
+    }))
+}
+
+/// Convert TOML values into JSON ones, dates and times become RFC 3339 strings
+fn toml_to_json(v: toml::Value) -> Value {
+    match v {
+        toml::Value::String(s) => Value::String(s),
+        toml::Value::Integer(i) => Value::from(i),
+        toml::Value::Float(f) => Value::from(f),
+        toml::Value::Boolean(b) => Value::Bool(b),
+        toml::Value::Datetime(d) => Value::String(d.to_string()),
+        toml::Value::Array(a) => Value::Array(a.into_iter().map(toml_to_json).collect()),
+        toml::Value::Table(t) => {
+            Value::Object(t.into_iter().map(|(k, v)| (k, toml_to_json(v))).collect())
+        }
+    }
+}
+
+/// Accept a single path as well as a list in config files, for options that used to take one
+fn one_or_many<'de, D>(d: D) -> Result<Vec<PathBuf>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(PathBuf),
+        Many(Vec<PathBuf>),
+    }
+    Ok(match OneOrMany::deserialize(d)? {
+        OneOrMany::One(path) => vec![path],
+        OneOrMany::Many(paths) => paths,
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogFormat {
+    Human,
+    Json,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(Self::Human),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("Unknown log format {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ErrorFormat {
+    Human,
+    Github,
+}
+
+impl std::str::FromStr for ErrorFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(Self::Human),
+            "github" => Ok(Self::Github),
+            _ => Err(format!("Unknown error format {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, StructOpt, Serialize, Deserialize)]
+#[structopt(name = "tplgen", about = "Template generator")]
+#[serde(rename_all = "kebab-case")]
+pub struct Opt {
+    /// Verbose output, use twice for debug output
+    #[structopt(short, long, parse(from_occurrences))]
+    pub verbose: u8,
+
+    /// Only output errors
+    #[structopt(short, long)]
+    pub quiet: bool,
+
+    /// Log format, `human` or `json`
+    #[structopt(long, default_value = "human", possible_values = &["human", "json"])]
+    pub log_format: LogFormat,
+
+    /// Also print template errors to stdout as GitHub Actions annotations with `github`,
+    /// pointing at the file and line of the template
+    #[structopt(long, default_value = "human", possible_values = &["human", "github"])]
+    pub error_format: ErrorFormat,
+
+    /// Log level, one of trace, debug, info, warn, error or off, overrides --verbose and --quiet
+    #[structopt(long)]
+    pub log_level: Option<LevelFilter>,
+
+    /// Config file with default values of the options, `tplgen.toml` or `.tplgenrc` in the
+    /// current directory if not present
+    #[structopt(long, parse(from_os_str))]
+    pub config: Option<PathBuf>,
+
+    /// Output directory, current directory if not present, rendered as a template against the
+    /// values like `out/{{env}}`
+    #[structopt(short, long, default_value = ".", parse(from_os_str))]
+    pub output: PathBuf,
+
+    /// Reject output paths outside `--output-root`
+    #[structopt(long)]
+    pub safe_paths: bool,
+
+    /// Octal permissions of the generated files like `0644`, Unix only, by default the outputs
+    /// of executable templates are executable too
+    #[structopt(long)]
+    pub chmod: Option<String>,
+
+    /// Do not make the outputs of executable templates executable, Unix only
+    #[structopt(long)]
+    pub no_preserve_mode: bool,
+
+    /// Modification time of the written files in seconds since the Unix epoch, for reproducible
+    /// builds, defaults to `SOURCE_DATE_EPOCH`
+    ///
+    /// It's set once the file is completely written, so it's the time seen by `--incremental`
+    /// and by archivers, outputs left unchanged keep their time.
+    #[structopt(long)]
+    pub mtime: Option<i64>,
+
+    /// Fail the templates rendering more than this size, like `10MB` or `1GiB`, before anything
+    /// is written
+    #[structopt(long)]
+    pub max_render_size: Option<String>,
+
+    /// Write outputs that already exist as pipes, devices or other non-regular files
+    #[structopt(long)]
+    pub allow_special: bool,
+
+    /// Write outputs that already exist as symlinks to their targets
+    #[structopt(long)]
+    pub follow_symlinks: bool,
+
+    /// Allow output paths outside the output directory, like template names or `forEach`
+    /// outputs containing `..`
+    #[structopt(long)]
+    pub allow_escape: bool,
+
+    /// Directory all output paths must stay in with `--safe-paths`
+    #[structopt(long, default_value = ".", parse(from_os_str))]
+    pub output_root: PathBuf,
+
+    /// Value file in JSON, YAML or TOML format, determined by its extension, or an `http://` or
+    /// `https://` URL, can be repeated with later files merged over earlier ones
+    #[structopt(short = "i", long = "values", number_of_values = 1, parse(from_os_str))]
+    #[serde(deserialize_with = "one_or_many")]
+    pub values: Vec<PathBuf>,
+
+    /// Fail when a `--values` file or URL cannot be read or parsed, instead of warning and
+    /// going on without it
+    #[structopt(long)]
+    pub require_values: bool,
+
+    /// Render string values like `"{{host}}:{{port}}"` against the values merged from all the
+    /// sources, values can reference values that reference others
+    #[structopt(long)]
+    pub resolve_refs: bool,
+
+    /// Turn keys like `server.port` in the values and the environment into nested objects, so
+    /// they can be used as `{{server.port}}`
+    #[structopt(long)]
+    pub expand_dotted_keys: bool,
+
+    /// Do not use the default values and templates built in with the `embedded` feature
+    #[structopt(long)]
+    pub no_embedded: bool,
+
+    /// Only allow the top-level keys of the first value file, keys added by the other sources
+    /// are reported as a `warn`ing or an `error`, environment variables only override them
+    #[structopt(long, possible_values = &["warn", "error"])]
+    pub closed_keys: Option<String>,
+
+    /// Value file merged after the `--values` files only when the key is truthy in the values
+    /// loaded so far or in the environment, given as `<key>=<path>` like `debug=extra.yaml`
+    #[structopt(long, number_of_values = 1)]
+    pub values_if: Vec<String>,
+
+    /// Maximum number of value files loaded and of templates rendered at the same time, `0`
+    /// or by default the number of CPUs, `1` renders the templates one after the other
+    #[structopt(long)]
+    pub jobs: Option<usize>,
+
+    /// Timeout in seconds when fetching the values from a URL
+    #[structopt(long, default_value = "30")]
+    pub values_timeout: u64,
+
+    /// How value files, documents and profiles are combined, `deep` where a `+key` appends to
+    /// the string or array under `key`, or `jsonmerge` for JSON Merge Patch where `null` removes
+    /// a key
+    #[structopt(long, default_value = "deep", possible_values = &["deep", "jsonmerge"])]
+    pub merge_mode: MergeMode,
+
+    /// Merge the `default` section and then this section of the value file into the root
+    #[structopt(long)]
+    pub profile: Option<String>,
+
+    /// Directory of per-key value files, the relative path of each file is used as a nested key
+    #[structopt(long, parse(from_os_str))]
+    pub values_tree: Option<PathBuf>,
+
+    /// Shell command whose standard output is merged into the values
+    #[structopt(long)]
+    pub values_cmd: Option<String>,
+
+    /// Format of the `--values-cmd` output, as a file extension like `json` or `yaml`
+    #[structopt(long, default_value = "json")]
+    pub values_cmd_format: String,
+
+    /// JSON object merged into the values after all the other sources
+    #[structopt(long, number_of_values = 1)]
+    pub json: Vec<String>,
+
+    /// Value set after all the other sources like `image.tag=1.4.2`, a dotted key sets a
+    /// nested value, booleans, numbers and `null` are typed, anything else is a string
+    #[structopt(short, long, number_of_values = 1)]
+    pub set: Vec<String>,
+
+    /// Remove this key from the values before rendering, a dotted path where segments can use
+    /// `*` and `?` wildcards, like `AWS_SECRET_*` or `db.password`
+    #[structopt(long, number_of_values = 1)]
+    pub omit_key: Vec<String>,
+
+    /// Print the source that set each value, to standard error
+    #[structopt(long)]
+    pub trace_values: bool,
+
+    /// Do not use environment variables
+    #[structopt(short, long)]
+    pub no_env: bool,
+
+    /// Fail unless this environment variable is set and not empty, even with `--no-env`
+    #[structopt(long, number_of_values = 1)]
+    pub require_env: Vec<String>,
+
+    /// Load `.env` and then `.env.<profile>` from the current directory as environment
+    /// variables, they override the value files and are overridden by the process environment
+    #[structopt(long)]
+    pub dotenv: bool,
+
+    /// Convert environment variables looking like booleans, numbers or null into typed values
+    #[structopt(long)]
+    pub coerce_env: bool,
+
+    /// Ignore the environment variables, including the `.env` ones, whose name matches this
+    /// glob like `*_TOKEN`, matched before `--case-insensitive-keys` lowercases the names
+    #[structopt(long, number_of_values = 1)]
+    pub env_exclude: Vec<String>,
+
+    /// Split the names of the environment variables at this separator like `__` into nested
+    /// lowercase keys, so `DATABASE__HOST` sets `{{database.host}}`, names without it like `PATH`
+    /// stay flat
+    #[structopt(long)]
+    pub env_separator: Option<String>,
+
+    /// Put values that are not a map under this key instead of discarding them when merging
+    /// environment variables
+    #[structopt(long)]
+    pub non_object_key: Option<String>,
+
+    /// Lowercase the keys of all value sources so keys differing only by case, like `Region`
+    /// and `REGION`, are merged, later sources win, templates must use the lowercase keys
+    #[structopt(long)]
+    pub case_insensitive_keys: bool,
+
+    /// Render templates against the value under this top-level key instead of the whole data
+    #[structopt(long)]
+    pub context_root: Option<String>,
+
+    /// Fail outputs with the `.json`, `.yaml`/`.yml` or `.toml` extension that don't parse
+    #[structopt(long)]
+    pub validate_output: bool,
+
+    /// Parse outputs with the `.yaml`/`.yml` or `.json` extension and serialize them again with
+    /// sorted keys and consistent indentation
+    #[structopt(long, possible_values = &["yaml", "json"])]
+    pub reformat: Option<String>,
+
+    /// Sort the keys of the objects in the merged values, so templates iterating over them
+    /// and serializing them get the same order whatever the order of the sources
+    #[structopt(long)]
+    pub sort_keys: bool,
+
+    /// Ignore the matches of this regex when comparing outputs with the existing files, so
+    /// outputs only differing by them are not rewritten, `^` and `$` match at line boundaries
+    #[structopt(long, number_of_values = 1)]
+    pub ignore_on_compare: Vec<String>,
+
+    /// Only replace the lines between the region markers of existing outputs, keeping the
+    /// rest of the file
+    #[structopt(long)]
+    pub region: bool,
+
+    /// Marker of the line starting the generated region, matched anywhere in the line
+    #[structopt(long, default_value = "tplgen:start")]
+    pub region_start: String,
+
+    /// Marker of the line ending the generated region, matched anywhere in the line
+    #[structopt(long, default_value = "tplgen:end")]
+    pub region_end: String,
+
+    /// Write the whole output when an existing output has no region markers, instead of
+    /// warning and leaving it untouched
+    #[structopt(long)]
+    pub region_fallback: bool,
+
+    /// Template of a header added as a comment at the top of each output, like
+    /// `Generated by tplgen from {{_template.name}}, do not edit`
+    #[structopt(long)]
+    pub banner: Option<String>,
+
+    /// Comment syntax of the banner
+    #[structopt(long, default_value = "#", possible_values = &["#", "//", "<!-- -->"])]
+    pub comment_style: String,
+
+    /// Extension of outputs without a banner, because they don't support comments
+    #[structopt(long, number_of_values = 1, default_value = "json")]
+    pub banner_skip: Vec<String>,
+
+    /// Name of the reserved variable holding the current template name and output path,
+    /// it is only added to the data while rendering
+    #[structopt(long, default_value = "_template")]
+    pub template_var: String,
+
+    /// Name of the reserved variable holding some of the options, like `output` and `profile`,
+    /// it is only added to the data while rendering, empty to leave it out
+    #[structopt(long, default_value = "_opt")]
+    pub opt_var: String,
+
+    /// Name of a reserved variable holding the environment variables, like `env` for
+    /// `{{env.HOME}}`, it is only added to the data while rendering, even with `--no-env`,
+    /// `--env-exclude` and `--dotenv` apply to it
+    #[structopt(long)]
+    pub expose_env: Option<String>,
+
+    /// Only expose the environment variables starting with this prefix with `--expose-env`
+    #[structopt(long)]
+    pub expose_env_prefix: Option<String>,
+
+    /// Output directory, current directory if not present
+    #[structopt(short, long, default_value = ".hbs")]
+    pub extension: String,
+
+    /// Match the template extension ignoring ASCII case, like `.HBS`, always on Windows and macOS
+    #[structopt(long)]
+    pub ignore_extension_case: bool,
+
+    /// Prefix the names of the templates in each input directory with the directory name, so
+    /// their outputs are in separate subdirectories. The prefix is part of the name used by
+    /// `--rename`, `--root-template` and to include a template as a partial, partials in
+    /// `.hbs.d` and `_partials` directories and input files are not prefixed.
+    #[structopt(long)]
+    pub preserve_input_root: bool,
+
+    /// Name the templates by their path relative to this directory instead of the input
+    /// directory, which must be under it, `--preserve-input-root` is then ignored
+    #[structopt(long, parse(from_os_str))]
+    pub template_root: Option<PathBuf>,
+
+    /// File name of a template without the template extension, like `Dockerfile`, the output
+    /// has the same name
+    #[structopt(long, number_of_values = 1)]
+    pub template_name: Vec<String>,
+
+    /// Lowercase the output paths, directories included, so they are the same on case
+    /// insensitive file systems
+    #[structopt(long)]
+    pub lowercase_paths: bool,
+
+    /// Only render the templates whose name matches this glob, like `config/*`, the other
+    /// templates are only used as partials
+    #[structopt(long, number_of_values = 1)]
+    pub include: Vec<String>,
+
+    /// Only render the templates whose source, or a partial they include, changed since this
+    /// git ref, all of them when a value file changed or outside a git repository
+    #[structopt(long)]
+    pub only_changed_since: Option<String>,
+
+    /// Split each output into files at the lines matching this marker, where `{path}` stands for
+    /// the file path relative to the output directory, like `# ---FILE: {path}---`
+    #[structopt(long)]
+    pub split_marker: Option<String>,
+
+    /// Write every output to standard output after a `==> path <==` header instead of files
+    #[structopt(long)]
+    pub output_stdout: bool,
+
+    /// Write every output to standard output as a stream of YAML documents separated by `---`
+    /// lines instead of files, also with `--output -`
+    #[structopt(long)]
+    pub stdout: bool,
+
+    /// Render every output without writing anything, printing its output path and size to
+    /// standard error
+    #[structopt(long)]
+    pub dry_run: bool,
+
+    /// Only render this template, the other templates are only used as partials
+    #[structopt(long)]
+    pub root_template: Option<String>,
+
+    /// Output file of `--root-template`, `<output>/<name>` if not present
+    #[structopt(long, parse(from_os_str))]
+    pub out_file: Option<PathBuf>,
+
+    /// Only check that all templates parse and render, without writing any file
+    #[structopt(long)]
+    pub check: bool,
+
+    /// `--check` with `--validate-output`, also reporting the outputs that don't parse as the
+    /// format of their extension
+    #[structopt(long)]
+    pub check_output: bool,
+
+    /// Compare the rendered outputs with the existing files without writing anything, fail if
+    /// any is missing or different
+    #[structopt(long)]
+    pub verify: bool,
+
+    /// Print a unified diff of the different outputs with `--verify`
+    #[structopt(long)]
+    pub diff: bool,
+
+    /// Report variables referenced by templates but missing in the data
+    #[structopt(long)]
+    pub report_missing: bool,
+
+    /// Rename outputs with `<regex>=<replacement>` rules on the template name, the first
+    /// matching rule wins, e.g. `(.*)\.yaml=$1.generated.yaml`
+    #[structopt(long, number_of_values = 1)]
+    pub rename: Vec<String>,
+
+    /// Skip templates whose output is newer than the template and the value files,
+    /// changes in environment variables are not detected
+    #[structopt(long)]
+    pub incremental: bool,
+
+    /// Skip templates whose source, partials, values and options hash to the same digest as in
+    /// the last run recorded in this file, unless their output changed. Helpers reading files,
+    /// the time or git are not detected.
+    #[structopt(long, parse(from_os_str))]
+    pub cache_file: Option<PathBuf>,
+
+    /// Render everything with `--cache-file`, the cache is then written again
+    #[structopt(long)]
+    pub no_cache: bool,
+
+    /// Do not overwrite outputs edited since the last run, recorded by digest in
+    /// `.tplgen-state` in the output directory
+    #[structopt(long)]
+    pub protect_edits: bool,
+
+    /// Tag each output with the `user.tplgen.template` and `user.tplgen.sha256` extended
+    /// attributes, on Unix file systems supporting them
+    #[structopt(long)]
+    pub xattr: bool,
+
+    /// Overwrite outputs edited since the last run with `--protect-edits`
+    #[structopt(long)]
+    pub force: bool,
+
+    /// Write the outputs into a staging directory next to the output directory, and only move
+    /// them into place once all templates succeeded, restoring the replaced outputs if a move
+    /// fails
+    #[structopt(long)]
+    pub transactional: bool,
+
+    /// JSON file keeping the values generated by `randomSecret`, so later runs return the same
+    /// ones. The secrets are stored in plain text, keep the file out of version control and
+    /// readable only by you, it is created with mode 0600 on Unix.
+    #[structopt(long, parse(from_os_str))]
+    pub seed_file: Option<PathBuf>,
+
+    /// Write all outputs, sorted by template name, into this single file, `-` for stdout
+    #[structopt(long, parse(from_os_str))]
+    pub concat: Option<PathBuf>,
+
+    /// Write the digests of the outputs into `SHA256SUMS` or `SHA512SUMS` in the output
+    /// directory, in the format checked by `sha256sum -c`
+    #[structopt(long, possible_values = &["sha256", "sha512"])]
+    pub checksums: Option<String>,
+
+    /// Write an index of the outputs into this file, a Markdown list of links unless
+    /// `--index-template`
+    #[structopt(long, parse(from_os_str))]
+    pub index: Option<PathBuf>,
+
+    /// Template of the `--index`, rendered with `files`, the outputs in rendering order, each with
+    /// its `path` relative to the output directory, file `name` and `size` in bytes, and
+    /// `count` and `bytes`, the number and total size of the outputs
+    #[structopt(long, parse(from_os_str))]
+    pub index_template: Option<PathBuf>,
+
+    /// Also write each output into the output directory with `--concat` or `--archive`
+    #[structopt(long)]
+    pub write_files: bool,
+
+    /// Separator between the outputs with `--concat`
+    #[structopt(long, default_value = "\n---\n")]
+    pub concat_separator: String,
+
+    /// Write all outputs into this tar or zip archive, chosen by extension, `-` for a tar on stdout
+    #[structopt(long, parse(from_os_str))]
+    pub archive: Option<PathBuf>,
+
+    /// Write the data paths, helpers and partials referenced by each template as JSON into this
+    /// file, `-` for stdout, and exit without rendering
+    #[structopt(long, parse(from_os_str))]
+    pub vars_report: Option<PathBuf>,
+
+    /// Write the values flattened into sorted `dotted.key=value` lines of a Java properties
+    /// file, `-` for stdout, and exit without rendering
+    #[structopt(long, parse(from_os_str))]
+    pub properties_out: Option<PathBuf>,
+
+    /// Print how each value source changes the values, in precedence order, then the merged
+    /// values and exit without rendering
+    #[structopt(long)]
+    pub preview_merge: bool,
+
+    /// Shell command run after generating when no template failed, its exit code becomes the
+    /// exit code of tplgen
+    #[structopt(long)]
+    pub post_hook: Option<String>,
+
+    /// Run the post hook even when templates failed
+    #[structopt(long)]
+    pub post_hook_always: bool,
+
+    /// Write a JSON summary of the run into this file, `-` for stderr
+    #[structopt(long, parse(from_os_str))]
+    pub summary_json: Option<PathBuf>,
+
+    /// Stop at the first template that fails to render or write
+    #[structopt(long)]
+    pub fail_fast: bool,
+
+    /// Process templates in the order they were registered, embedded ones then the inputs in
+    /// order with the files of a directory as the file system lists them, instead of sorted by
+    /// name, values stored by `setGlobal` may then not be visible to the templates reading them
+    #[structopt(long)]
+    pub no_sort: bool,
+
+    /// Show a progress bar when stderr is a terminal
+    #[structopt(long)]
+    pub progress: bool,
+
+    /// Encoding of template sources that are not valid UTF-8, like `latin1` or `shift_jis`
+    #[structopt(long)]
+    pub encoding: Option<String>,
+
+    /// Convert CRLF line endings in template sources to LF
+    #[structopt(long)]
+    pub normalize_input_eol: bool,
+
+    /// Do not register any built-in helper
+    #[structopt(long)]
+    pub no_builtin_helpers: bool,
+
+    /// Do not register this built-in helper
+    #[structopt(long, number_of_values = 1)]
+    pub disable_helper: Vec<String>,
+
+    /// Helper applying helpers in order to its parameter, as `<name>=<helper>|<helper>` like
+    /// `slug=trim|lowercase`, usually declared in the config file to share such conventions
+    #[structopt(long, number_of_values = 1)]
+    pub compose_helper: Vec<String>,
+
+    /// WebAssembly module registered as a helper named after the file stem, needs the `wasm`
+    /// feature
+    #[structopt(long, number_of_values = 1, parse(from_os_str))]
+    pub wasm_helper: Vec<PathBuf>,
+
+    /// Fail the templates referencing a value missing from the data instead of rendering it
+    /// empty
+    #[structopt(long)]
+    pub strict: bool,
+
+    /// Fail the render when a built-in helper gets an invalid value instead of warning, and
+    /// fail when templates include each other in a loop or miss their `requires` values
+    /// instead of skipping them
+    #[structopt(long)]
+    pub strict_helpers: bool,
+
+    /// Print a completion script for this shell and exit
+    #[structopt(long, possible_values = &structopt::clap::Shell::variants())]
+    pub completions: Option<String>,
+
+    /// Create a starter project with a template, a value file and a config file in the
+    /// current directory
+    #[structopt(long)]
+    pub init: bool,
+
+    /// Directory or file name of the template files
+    pub input: Vec<PathBuf>,
+}
+
+/// Set the options from the `TPLGEN_*` environment variables in the merged options, but the
+/// ones given on the command line, returning warnings about the unknown ones
+///
+/// The values of lists are split on commas and the ones of booleans and numbers are coerced,
+/// as the ones of unset optional options when the options still deserialize with the typed value,
+/// so `TPLGEN_JOBS=2` is a number but `TPLGEN_CHMOD=644` stays a string.
+fn merge_env(
+    merged: &mut serde_json::Map<String, Value>,
+    vars: impl IntoIterator<Item = (String, String)>,
+    given: impl Fn(&str) -> bool,
+) -> Vec<String> {
+    let mut warnings = vec![];
+    let mut optional = vec![];
+    for (name, value) in vars {
+        let key = match name.strip_prefix("TPLGEN_") {
+            Some(k) => k.to_ascii_lowercase().replace('_', "-"),
+            None => continue,
+        };
+        // `config` can't be set here and hooks get the details of the run
+        if key == "config" || key.starts_with("hook-") {
+            continue;
+        }
+        let current = match merged.get_mut(&key) {
+            Some(v) => v,
+            None => {
+                warnings.push(format!("Unknown option in environment variable {}", name));
+                continue;
+            }
+        };
+        if given(&key) {
+            continue;
+        }
+        *current = match current {
+            Value::Array(_) => value
+                .split(',')
+                .filter(|s| !s.is_empty())
+                .map(|s| Value::String(s.to_owned()))
+                .collect(),
+            Value::Bool(_) | Value::Number(_) => coerce_scalar(&value),
+            Value::Null => {
+                optional.push(key.clone());
+                Value::String(value)
+            }
+            _ => Value::String(value),
+        };
+    }
+    // Each value is tried with the other optional options unset, they are still strings
+    let mut unset = merged.clone();
+    for key in &optional {
+        unset.insert(key.clone(), Value::Null);
+    }
+    for key in optional {
+        let value = match merged.get(&key) {
+            Some(Value::String(s)) => coerce_scalar(s),
+            _ => continue,
+        };
+        if value.is_string() {
+            continue;
+        }
+        let mut trial = unset.clone();
+        trial.insert(key.clone(), value.clone());
+        if serde_json::from_value::<Opt>(Value::Object(trial)).is_ok() {
+            merged.insert(key, value);
+        }
+    }
+    warnings
+}
+
+/// The options of the command line without arguments, so embedders can set the fields they
+/// need like `Opt { input: vec!["templates".into()], ..Default::default() }`
+impl Default for Opt {
+    fn default() -> Self {
+        Self::from_iter(["tplgen"])
+    }
+}
+
+impl Opt {
+    /// Parse the command line, options not given there are taken from `TPLGEN_*` environment
+    /// variables, then from the config file
+    ///
+    /// The environment variable of an option is its long name in upper snake case, e.g.
+    /// `TPLGEN_NO_ENV` for `--no-env`, list values are separated by commas.
+    ///
+    /// The config file is `--config` or `TPLGEN_CONFIG`, or `tplgen.toml` or `.tplgenrc` in the
+    /// current directory, in TOML format with the long option names as keys. Returns the
+    /// options, with `config` set to the config file in use, and the warnings to log once the
+    /// logger is set up, or an error when the config file can't be read or the options are
+    /// invalid.
+    pub fn load() -> Result<(Self, Vec<String>)> {
+        let matches = Self::clap().get_matches();
+        let opt = Self::from_clap(&matches);
+        let mut warnings = vec![];
+        let mut merged = match to_value(&opt) {
+            Ok(Value::Object(m)) => m,
+            _ => unreachable!("options are serialized as a map"),
+        };
+
+        let path = opt
+            .config
+            .clone()
+            .or_else(|| std::env::var_os("TPLGEN_CONFIG").map(PathBuf::from))
+            .or_else(|| {
+                ["tplgen.toml", ".tplgenrc"]
+                    .iter()
+                    .map(PathBuf::from)
+                    .find(|p| p.is_file())
+            });
+        if let Some(path) = &path {
+            let config = std::fs::read_to_string(path)
+                .map_err(anyhow::Error::from)
+                .and_then(|s| Ok(to_value(toml::from_str::<toml::Value>(&s)?)?));
+            let config = match config {
+                Ok(Value::Object(m)) => m,
+                Ok(_) => unreachable!("TOML documents are tables"),
+                Err(e) => {
+                    let path = path.to_string_lossy();
+                    return Err(e.context(format!("Cannot read config file {}", path)));
+                }
+            };
+            for (k, v) in config {
+                if k == "config" || !merged.contains_key(&k) {
+                    warnings.push(format!("Unknown option {} in config file", k));
+                } else if matches.occurrences_of(&k) == 0 {
+                    merged.insert(k, v);
+                }
+            }
+            merged.insert("config".to_owned(), to_value(path).unwrap_or_default());
+        }
+
+        let env = std::env::vars().filter(|(name, _)| name.starts_with("TPLGEN_"));
+        warnings.extend(merge_env(&mut merged, env, |k| matches.occurrences_of(k) > 0));
+
+        let opt = serde_json::from_value(Value::Object(merged))
+            .context("Invalid options in config file or environment")?;
+        Ok((opt, warnings))
+    }
+
+    /// Options of these command line arguments, without the program name, the config file
+    /// and the environment, e.g. `Opt::parse(["-i", "values.yaml", "templates"])`
+    pub fn parse<I, T>(args: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<std::ffi::OsString> + Clone,
+    {
+        let args = std::iter::once("tplgen".into()).chain(args.into_iter().map(Into::into));
+        Ok(Self::from_iter_safe(args)?)
+    }
+
+    fn get_ext(&self) -> String {
+        if self.extension.starts_with('.') {
+            self.extension.to_owned()
+        } else {
+            format!(".{}", self.extension)
+        }
+    }
+}
+
+/// Renders a tree of templates against the merged values
+#[derive(Debug)]
+pub struct Generator {
+    data: Value,
+    opt: Opt,
+    engine: Handlebars<'static>,
+    /// State of the built-in helpers registered in the engine
+    helper_state: Arc<helpers::HelperState>,
+    /// Templates found in the inputs
+    scan: Scan,
+    /// Rules renaming template names into output names
+    renames: Vec<(Regex, String)>,
+    /// Names of the helpers registered with `register_helper`
+    custom_helpers: HashSet<String>,
+    /// Header rendered as a comment at the top of each output
+    banner: Option<Template>,
+    /// Environment variables exposed to the templates with `--expose-env`
+    env: Option<Value>,
+    /// Patterns removed from outputs before comparing them with the existing files
+    compare_ignores: Vec<Regex>,
+    /// Templates affected by the changes since `--only-changed-since`, `None` for all
+    affected: Option<HashSet<String>>,
+    /// Digests of the inputs and the output of each output with `--cache-file`
+    cache: Mutex<BTreeMap<String, CacheEntry>>,
+    /// Digests of the outputs by path relative to the output directory with `--protect-edits`
+    state: Mutex<BTreeMap<String, String>>,
+    /// Staging directory of `--transactional`
+    staging: Option<PathBuf>,
+    /// Warns once that the `--context-root` key is missing, the context is used by every template
+    missing_context_root: std::sync::Once,
+    /// Outputs written into the staging directory, with their final path
+    staged: Mutex<Vec<(PathBuf, PathBuf)>>,
+    summary: Mutex<Summary>,
+}
+
+/// Content of the `--cache-file`
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Cache {
+    /// Version of tplgen writing the cache, the cache of another version is ignored
+    version: String,
+    /// Entry of each output by path relative to the output directory
+    entries: BTreeMap<String, CacheEntry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CacheEntry {
+    /// Digest of everything the output was rendered from
+    input: String,
+    /// Digest of the output file
+    output: String,
+}
+
+/// Statistics of a run
+#[derive(Debug, Default, Serialize)]
+struct Summary {
+    created: usize,
+    updated: usize,
+    skipped: usize,
+    failed: usize,
+    /// Total bytes written
+    bytes: usize,
+    failures: Vec<Failure>,
+    /// Outputs in the output directory after the run, written or already up to date
+    #[serde(skip)]
+    outputs: Vec<PathBuf>,
+    /// Template of each of the `outputs`
+    #[serde(skip)]
+    output_templates: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct Failure {
+    template: String,
+    error: String,
+}
+
+impl Summary {
+    fn record(&mut self, existed: bool, bytes: usize) {
+        if existed {
+            self.updated += 1;
+        } else {
+            self.created += 1;
+        }
+        self.bytes += bytes;
+    }
+
+    fn output(&mut self, template: &str, path: &Path) {
+        self.outputs.push(path.to_owned());
+        self.output_templates.push(template.to_owned());
+    }
+
+    fn fail(&mut self, template: &str, error: &anyhow::Error) {
+        self.failed += 1;
+        self.failures.push(Failure {
+            template: template.to_owned(),
+            error: format!("{:#}", error),
+        });
+    }
+}
+
+/// A registered template
+#[derive(Debug)]
+struct TemplateInfo {
+    /// Template source file
+    source: PathBuf,
+    front: FrontMatter,
+}
+
+/// One output file of a template
+#[derive(Debug)]
+struct Job {
+    out_path: PathBuf,
+    /// The data to render
+    data: Value,
+    /// Index of the item in the `forEach` array, available as `@index`
+    index: Option<usize>,
+}
+
+/// `Output` writing into a `Write`
+struct WriteOutput<W: Write>(W);
+
+impl<W: Write> Output for WriteOutput<W> {
+    fn write(&mut self, seg: &str) -> std::io::Result<()> {
+        self.0.write_all(seg.as_bytes())
+    }
+}
+
+/// Changes of the values after each source with `--preview-merge`
+struct MergePreview {
+    last: String,
+}
+
+impl Default for MergePreview {
+    fn default() -> Self {
+        Self { last: "{}\n".to_owned() }
+    }
+}
+
+impl MergePreview {
+    /// Print the changes made by the source as a unified diff of the pretty-printed values
+    fn step(&mut self, source: &str, data: &Value) {
+        let next = serde_json::to_string_pretty(data).unwrap_or_default() + "\n";
+        println!("==> {} <==", source);
+        if next == self.last {
+            println!("(no change)");
+        } else {
+            let diff = similar::TextDiff::from_lines(&self.last, &next);
+            print!("{}", diff.unified_diff().header("before", "after"));
+        }
+        self.last = next;
+    }
+}
+
+/// `Write` failing when more than `limit` bytes are written, for `--max-render-size`
+struct LimitedWriter<W: Write> {
+    inner: W,
+    limit: u64,
+    written: u64,
+}
+
+impl<W: Write> Write for LimitedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.written + buf.len() as u64 > self.limit {
+            return Err(std::io::Error::other(format!(
+                "Output larger than --max-render-size of {} bytes",
+                self.limit
+            )));
+        }
+        let n = self.inner.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Result of scanning the inputs for templates
+#[derive(Debug, Default)]
+struct Scan {
+    /// Each registered template, partials excluded
+    templates: HashMap<String, TemplateInfo>,
+    /// Names of the templates in the order they were registered, for `--no-sort`
+    order: Vec<String>,
+    /// Source file of each registered partial
+    partials: HashMap<String, PathBuf>,
+    /// Errors of templates failed to register
+    errors: Vec<handlebars::TemplateError>,
+}
+
+impl Generator {
+    /// Generator of these command line arguments, without the program name, see `Opt::parse`
+    pub fn from_args<I, T>(args: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<std::ffi::OsString> + Clone,
+    {
+        Self::new(Opt::parse(args)?)
+    }
+
+    /// Load the values and register the templates given by the options
+    pub fn new(opt: Opt) -> Result<Self> {
+        let missing: Vec<&str> = opt
+            .require_env
+            .iter()
+            .filter(|name| std::env::var_os(name).is_none_or(|v| v.is_empty()))
+            .map(String::as_str)
+            .collect();
+        if !missing.is_empty() {
+            anyhow::bail!("Missing required environment variables: {}", missing.join(", "));
+        }
+        if let Some(root) = &opt.template_root {
+            let root = normalize_path(root);
+            let mut dirs = opt.input.iter().filter(|input| input.is_dir());
+            if let Some(input) = dirs.find(|input| !normalize_path(input).starts_with(&root)) {
+                anyhow::bail!(
+                    "Input {} is not under --template-root {}",
+                    input.to_string_lossy(),
+                    root.to_string_lossy()
+                );
+            }
+        }
+        let data = Self::get_data(&opt)?;
+        let helper_state = Arc::new(helpers::HelperState::new(opt.strict_helpers));
+        let (mut engine, mut scan) = Self::get_engine(&opt, &helper_state);
+        let custom_helpers = Self::register_composed_helpers(&mut engine, &opt);
+        Self::check_include_cycles(&engine, &mut scan, opt.strict_helpers)?;
+        let affected = opt
+            .only_changed_since
+            .as_deref()
+            .and_then(|git_ref| Self::affected_templates(&opt, &engine, &scan, git_ref));
+        let renames = Self::get_renames(&opt);
+        let compare_ignores = Self::get_compare_ignores(&opt);
+        if let Some(mode) = opt.chmod.as_deref().filter(|m| parse_mode(m).is_none()) {
+            anyhow::bail!("Invalid --chmod {}, expecting octal permissions like 0644", mode);
+        }
+        let max_size = opt.max_render_size.as_deref();
+        if let Some(size) = max_size.filter(|s| helpers::parse_bytes(s).is_none()) {
+            anyhow::bail!("Invalid --max-render-size {}, expecting a size like 10MB", size);
+        }
+        if let Some(path) = &opt.seed_file {
+            helper_state.set_seeds(Self::load_seeds(path)?);
+        }
+        let banner = match &opt.banner {
+            Some(banner) => Some(Template::compile(banner).context("Invalid banner template")?),
+            None => None,
+        };
+        let env = Self::exposed_env(&opt);
+        let mut app = Self {
+            data,
+            opt,
+            engine,
+            helper_state,
+            scan,
+            renames,
+            custom_helpers,
+            banner,
+            env,
+            compare_ignores,
+            affected,
+            cache: Default::default(),
+            state: Default::default(),
+            staging: None,
+            missing_context_root: std::sync::Once::new(),
+            staged: Default::default(),
+            summary: Default::default(),
+        };
+        if app.opt.output == Path::new("-") {
+            app.opt.stdout = true;
+            app.opt.output = PathBuf::from(".");
+        }
+        app.opt.output = app.resolve_output()?;
+        if app.opt.stdout {
+            app.opt.output_stdout = true;
+        }
+        if app.opt.transactional {
+            app.staging = Some(app.staging_dir());
+        }
+        if app.opt.check_output {
+            app.opt.check = true;
+            app.opt.validate_output = true;
+        }
+        if app.opt.mtime.is_none() {
+            app.opt.mtime = helpers::source_date_epoch();
+        }
+        if app.opt.protect_edits {
+            app.state = Mutex::new(app.load_state());
+        }
+        if let Some(path) = app.opt.cache_file.as_deref().filter(|_| !app.opt.no_cache) {
+            app.cache = Mutex::new(Self::load_cache(path));
+        }
+        Ok(app)
+    }
+
+    /// Register the `--compose-helper` helpers, made of enabled built-in helpers and helpers
+    /// composed before them, returns their names
+    fn register_composed_helpers(engine: &mut Handlebars, opt: &Opt) -> HashSet<String> {
+        let mut known: HashSet<String> = helpers::builtin_helpers()
+            .into_iter()
+            .map(|(name, _)| name.to_owned())
+            .filter(|name| !opt.no_builtin_helpers && !opt.disable_helper.contains(name))
+            .collect();
+        let mut names = HashSet::new();
+        for def in &opt.compose_helper {
+            let (name, steps) = match def.split_once('=') {
+                Some((name, steps)) if !name.trim().is_empty() => (name.trim(), steps),
+                _ => {
+                    warn!("Invalid --compose-helper {}, expecting `<name>=<helper>|...`", def);
+                    continue;
+                }
+            };
+            let steps: Vec<&str> = steps.split('|').map(str::trim).collect();
+            if let Some(unknown) = steps.iter().find(|s| !known.contains(**s)) {
+                warn!("Unknown helper {}, skipping --compose-helper {}", unknown, def);
+                continue;
+            }
+            if let Some(helper) = helpers::Composed::new(&steps) {
+                debug!("Registered helper {} as {}", name, steps.join(" then "));
+                engine.register_helper(name, Box::new(helper));
+                known.insert(name.to_owned());
+                names.insert(name.to_owned());
+            }
+        }
+        names
+    }
+
+    /// Files added, modified or deleted in the working tree since the git ref, `None` outside
+    /// a git repository or for an unknown ref
+    fn changed_files(git_ref: &str) -> Option<HashSet<PathBuf>> {
+        let top = helpers::git(&["rev-parse", "--show-toplevel"])?;
+        let diff = helpers::git(&["-C", &top, "diff", "--name-only", git_ref, "--"])?;
+        let untracked = helpers::git(&["-C", &top, "ls-files", "--others", "--exclude-standard"])
+            .unwrap_or_default();
+        let top = PathBuf::from(top);
+        let files = diff.lines().chain(untracked.lines()).filter(|l| !l.is_empty());
+        Some(files.map(|f| canonical_path(&top.join(f))).collect())
+    }
+
+    /// Templates whose source or included partials changed since the git ref, `None` when all
+    /// templates must be rendered
+    fn affected_templates(
+        opt: &Opt,
+        engine: &Handlebars,
+        scan: &Scan,
+        git_ref: &str,
+    ) -> Option<HashSet<String>> {
+        let changed = match Self::changed_files(git_ref) {
+            Some(changed) => changed,
+            None => {
+                warn!("Cannot list the changes since {} with git, rendering everything", git_ref);
+                return None;
+            }
+        };
+        let mut values: Vec<PathBuf> = opt.values.clone();
+        let values_if = opt.values_if.iter().filter_map(|e| e.split_once('='));
+        values.extend(values_if.map(|(_, path)| PathBuf::from(path)));
+        values.extend(opt.config.clone());
+        if opt.dotenv {
+            values.push(".env".into());
+            values.extend(opt.profile.iter().map(|p| PathBuf::from(format!(".env.{}", p))));
+        }
+        let values_tree = opt.values_tree.as_deref().map(canonical_path);
+        let values_changed = changed.iter().any(|path| {
+            values.iter().any(|v| canonical_path(v) == *path)
+                || values_tree.as_ref().is_some_and(|dir| path.starts_with(dir))
+        });
+        if values_changed {
+            info!("Value files changed since {}, rendering all templates", git_ref);
+            return None;
+        }
+        let sources = scan.templates.iter().map(|(name, t)| (name, &t.source));
+        let mut affected: HashSet<String> = sources
+            .chain(&scan.partials)
+            .filter(|(_, source)| changed.contains(&canonical_path(source)))
+            .map(|(name, _)| name.clone())
+            .collect();
+        // templates including an affected template or partial are affected too
+        loop {
+            let before = affected.len();
+            for (name, t) in engine.get_templates() {
+                if !affected.contains(name)
+                    && inspect::included_partials(t).iter().any(|p| affected.contains(p))
+                {
+                    affected.insert(name.clone());
+                }
+            }
+            if affected.len() == before {
+                break;
+            }
+        }
+        affected.retain(|name| scan.templates.contains_key(name));
+        info!(
+            "{} of {} templates affected by the changes since {}",
+            affected.len(),
+            scan.templates.len(),
+            git_ref
+        );
+        Some(affected)
+    }
+
+    /// Report templates including each other in a loop, which would overflow the stack when
+    /// rendered, and drop the templates reaching a loop unless `strict` where it's an error
+    fn check_include_cycles(engine: &Handlebars, scan: &mut Scan, strict: bool) -> Result<()> {
+        let cycles = inspect::include_cycles(engine.get_templates());
+        if cycles.is_empty() {
+            return Ok(());
+        }
+        let loops: Vec<String> = cycles.iter().map(|c| c.join(" -> ")).collect();
+        if strict {
+            anyhow::bail!("Circular includes: {}", loops.join(", "));
+        }
+        for l in &loops {
+            warn!("Circular include {}", l);
+        }
+        let looping: HashSet<&String> = cycles.iter().flatten().collect();
+        let reaches_loop = |name: &String| {
+            let mut seen = HashSet::new();
+            let mut todo = vec![name.clone()];
+            while let Some(name) = todo.pop() {
+                if looping.contains(&name) {
+                    return true;
+                }
+                if let Some(t) = engine.get_template(&name) {
+                    todo.extend(inspect::included_partials(t).into_iter().filter(|n| seen.insert(n.clone())));
+                }
+            }
+            false
+        };
+        scan.templates.retain(|name, _| {
+            let keep = !reaches_loop(name);
+            if !keep {
+                warn!("Skipping template {}, it ends up including itself", name);
+            }
+            keep
+        });
+        Ok(())
+    }
+
+    /// The options in use, `output` is the rendered output directory
+    pub fn opt(&self) -> &Opt {
+        &self.opt
+    }
+
+    /// The merged values
+    pub fn data(&self) -> &Value {
+        &self.data
+    }
+
+    /// Register a helper, replacing any built-in helper with the same name
+    ///
+    /// Templates are only rendered by `render_all` and `check`, so the helper is used by
+    /// every template as long as it's registered before them.
+    pub fn register_helper(&mut self, name: &str, helper: Box<dyn HelperDef + Send + Sync>) {
+        self.engine.register_helper(name, helper);
+        self.custom_helpers.insert(name.to_owned());
+    }
+
+    /// Render the output directory against the values and check it with `--safe-paths`
+    fn resolve_output(&self) -> Result<PathBuf> {
+        let raw = self.opt.output.to_string_lossy();
+        let output = if raw.contains("{{") {
+            let rendered = self
+                .engine
+                .render_template(&raw, self.context())
+                .with_context(|| format!("Failed to render output directory {}", raw))?;
+            debug!("Output directory {} => {}", raw, rendered);
+            PathBuf::from(rendered.trim())
+        } else {
+            self.opt.output.clone()
+        };
+        self.check_safe_path(&output)?;
+        Ok(output)
+    }
+
+    /// Fail if `--safe-paths` is set and the path is outside `--output-root`
+    fn check_safe_path(&self, path: &Path) -> Result<()> {
+        let root = normalize_path(&self.opt.output_root);
+        if self.opt.safe_paths && !normalize_path(path).starts_with(&root) {
+            anyhow::bail!(
+                "Output path {} is outside {}",
+                path.to_string_lossy(),
+                self.opt.output_root.to_string_lossy()
+            );
+        }
+        Ok(())
+    }
+
+    /// Variables of the `.env` files with `--dotenv` then of the process environment, without
+    /// the `--env-exclude` ones
+    fn env_vars(opt: &Opt) -> Vec<(String, String)> {
+        let mut files = vec![];
+        if opt.dotenv {
+            files.push(PathBuf::from(".env"));
+            if let Some(profile) = &opt.profile {
+                files.push(PathBuf::from(format!(".env.{}", profile)));
+            }
+        }
+        let dotenv = files.iter().filter_map(|path| read_dotenv(path)).flatten();
+        let exclude: Vec<_> = opt.env_exclude.iter().filter_map(|g| glob_regex(g)).collect();
+        dotenv
+            .chain(std::env::vars())
+            .filter(|(k, _)| {
+                let excluded = exclude.iter().any(|re| re.is_match(k));
+                if excluded {
+                    debug!("Excluding environment variable {}", k);
+                }
+                !excluded
+            })
+            .collect()
+    }
+
+    /// Environment variables exposed under `--expose-env`
+    fn exposed_env(opt: &Opt) -> Option<Value> {
+        opt.expose_env.as_ref()?;
+        let prefix = opt.expose_env_prefix.as_deref().unwrap_or_default();
+        let vars = Self::env_vars(opt).into_iter().filter(|(k, _)| k.starts_with(prefix));
+        let vars = vars.map(|(k, v)| {
+            let v = if opt.coerce_env {
+                coerce_scalar(&v)
+            } else {
+                Value::String(v)
+            };
+            (k, v)
+        });
+        Some(Value::Object(vars.collect()))
+    }
+
+    fn get_data(opt: &Opt) -> Result<Value> {
+        let canonical = |v: Value| {
+            let v = if opt.expand_dotted_keys {
+                expand_dotted_keys(v)
+            } else {
+                v
+            };
+            if opt.case_insensitive_keys {
+                lowercase_keys(v)
+            } else {
+                v
+            }
+        };
+        let mut trace = opt.trace_values.then(BTreeMap::new);
+        let mut preview = opt.preview_merge.then(MergePreview::default);
+        let def = serde_json::Value::Object(serde_json::Map::default());
+        let embedded = embedded::values().filter(|_| !opt.no_embedded).map(canonical);
+        if let (Some(trace), Some(embedded)) = (&mut trace, &embedded) {
+            trace_leaves(trace, "", embedded, "embedded");
+        }
+        let mut merged: Option<Value> = None;
+        // top-level keys of the first value file with `--closed-keys`, and keys added later
+        let mut closed: Option<BTreeSet<String>> = None;
+        let mut unknown = vec![];
+        for (path, obj) in opt.values.iter().zip(Self::load_values(opt)) {
+            if obj.is_none() && opt.require_values {
+                anyhow::bail!("Cannot load value file {}", path.to_string_lossy());
+            }
+            let mut obj = obj.unwrap_or_else(|| def.clone());
+            if let Some(profile) = &opt.profile {
+                Self::apply_profile(&mut obj, profile, opt.merge_mode);
+            }
+            let obj = canonical(obj);
+            if let Some(trace) = &mut trace {
+                trace_leaves(trace, "", &obj, &path.to_string_lossy());
+            }
+            match &mut merged {
+                Some(merged) => {
+                    if let Some(closed) = &closed {
+                        unknown.extend(unknown_keys(closed, &obj, &path.to_string_lossy()));
+                    }
+                    opt.merge_mode.merge(merged, obj)
+                }
+                None => {
+                    if opt.closed_keys.is_some() {
+                        let keys = obj.as_object().map(|m| m.keys().cloned().collect());
+                        closed = Some(keys.unwrap_or_default());
+                    }
+                    merged = Some(obj)
+                }
+            }
+            if let (Some(preview), Some(merged)) = (&mut preview, &merged) {
+                preview.step(&path.to_string_lossy(), merged);
+            }
+        }
+        if opt.closed_keys.is_some() && closed.is_none() {
+            warn!("--closed-keys needs a value file, ignored");
+        }
+        let mut obj = merged.unwrap_or(def);
+        if let Some(mut embedded) = embedded {
+            opt.merge_mode.merge(&mut embedded, obj);
+            obj = embedded;
+            if let Some(preview) = &mut preview {
+                preview.step("embedded values, under the value files", &obj);
+            }
+        }
+
+        for entry in &opt.values_if {
+            let (key, path) = match entry.split_once('=') {
+                Some(kv) => kv,
+                None => {
+                    warn!("Invalid --values-if {}, expecting `<key>=<path>`", entry);
+                    continue;
+                }
+            };
+            let in_data = inspect::lookup(&obj, key).is_some_and(is_truthy);
+            let in_env = !opt.no_env
+                && std::env::var(key).is_ok_and(|v| is_truthy(&coerce_scalar(&v)));
+            if !in_data && !in_env {
+                debug!("Skipping value file {}, {} is not set", path, key);
+                continue;
+            }
+            if let Some(mut v) = Self::load_values_source(Path::new(path), opt) {
+                if let Some(profile) = &opt.profile {
+                    Self::apply_profile(&mut v, profile, opt.merge_mode);
+                }
+                let v = canonical(v);
+                if let Some(trace) = &mut trace {
+                    trace_leaves(trace, "", &v, path);
+                }
+                if let Some(closed) = &closed {
+                    unknown.extend(unknown_keys(closed, &v, path));
+                }
+                opt.merge_mode.merge(&mut obj, v);
+                if let Some(preview) = &mut preview {
+                    preview.step(path, &obj);
+                }
+            }
+        }
+
+        let obj = match &opt.values_tree {
+            Some(dir) => {
+                let mut obj = obj;
+                let tree = canonical(Self::read_values_tree(dir));
+                if let Some(trace) = &mut trace {
+                    trace_leaves(trace, "", &tree, &dir.to_string_lossy());
+                }
+                if let Some(closed) = &closed {
+                    unknown.extend(unknown_keys(closed, &tree, &dir.to_string_lossy()));
+                }
+                opt.merge_mode.merge(&mut obj, tree);
+                if let Some(preview) = &mut preview {
+                    preview.step(&dir.to_string_lossy(), &obj);
+                }
+                obj
+            }
+            None => obj,
+        };
+
+        let obj = match opt.values_cmd.as_deref().and_then(|cmd| Self::run_values_cmd(opt, cmd)) {
+            Some(v) => {
+                let mut obj = obj;
+                let v = canonical(v);
+                if let Some(trace) = &mut trace {
+                    trace_leaves(trace, "", &v, "--values-cmd");
+                }
+                if let Some(closed) = &closed {
+                    unknown.extend(unknown_keys(closed, &v, "--values-cmd"));
+                }
+                opt.merge_mode.merge(&mut obj, v);
+                if let Some(preview) = &mut preview {
+                    preview.step("--values-cmd", &obj);
+                }
+                obj
+            }
+            None => obj,
+        };
+
+        let data = if !opt.no_env {
+            debug!("Using environment variables");
+            let mapping = match (obj, &opt.non_object_key) {
+                (Value::Object(m), _) => m,
+                (obj, Some(key)) => {
+                    debug!("Value file is not a map, putting it under {}", key);
+                    let mut m = serde_json::Map::default();
+                    m.insert(key.clone(), obj);
+                    m
+                }
+                _ => {
+                    warn!("Value file is not a map.");
+                    Default::default()
+                }
+            };
+            let sep = opt.env_separator.as_deref().filter(|sep| !sep.is_empty());
+            let mut vars = Self::env_vars(opt);
+            // with a separator the nested variables are merged last so they win over the flat ones
+            if let Some(sep) = sep {
+                vars.sort_by_key(|(k, _)| k.matches(sep).count());
+            }
+            let mut data = Value::Object(mapping);
+            for (k, v) in vars {
+                let v = if opt.coerce_env {
+                    coerce_scalar(&v)
+                } else {
+                    Value::String(v)
+                };
+                let k = if opt.case_insensitive_keys {
+                    k.to_lowercase()
+                } else {
+                    k
+                };
+                let keys = env_keys(&k, sep);
+                // the environment is full of unrelated variables, only override known keys
+                if closed.as_ref().is_some_and(|closed| !closed.contains(&keys[0])) {
+                    continue;
+                }
+                if let Some(trace) = &mut trace {
+                    trace.insert(keys.join("."), "env".to_owned());
+                }
+                if sep.is_none() {
+                    data[&k] = v;
+                } else if let Some(v) = nested_env_value(&data, &keys, v, &k) {
+                    merge(&mut data, v);
+                }
+            }
+            let data = if opt.expand_dotted_keys {
+                expand_dotted_keys(data)
+            } else {
+                data
+            };
+            if let Some(preview) = &mut preview {
+                preview.step("environment", &data);
+            }
+            data
+        } else {
+            debug!("Not using environment variables");
+            obj
+        };
+
+        let data = opt.json.iter().fold(data, |mut data, blob| {
+            match serde_json::from_str::<Value>(blob) {
+                Ok(v @ Value::Object(_)) => {
+                    let v = canonical(v);
+                    if let Some(trace) = &mut trace {
+                        trace_leaves(trace, "", &v, "--json");
+                    }
+                    if let Some(closed) = &closed {
+                        unknown.extend(unknown_keys(closed, &v, "--json"));
+                    }
+                    opt.merge_mode.merge(&mut data, v);
+                    if let Some(preview) = &mut preview {
+                        preview.step(&format!("--json {}", blob), &data);
+                    }
+                }
+                Ok(_) => warn!("--json {} is not an object, skipped", blob),
+                Err(e) => warn!("Invalid --json {}, {}", blob, e),
+            }
+            data
+        });
+
+        let data = opt.set.iter().fold(data, |mut data, entry| {
+            let (key, value) = match entry.split_once('=') {
+                Some((key, value)) if !key.is_empty() => (key, value),
+                _ => {
+                    warn!("Invalid --set {}, expecting `<key>=<value>`, skipped", entry);
+                    return data;
+                }
+            };
+            let v = key.rsplit('.').fold(coerce_scalar(value), |v, segment| {
+                let mut m = serde_json::Map::new();
+                m.insert(segment.to_owned(), v);
+                Value::Object(m)
+            });
+            let v = canonical(v);
+            if let Some(trace) = &mut trace {
+                trace_leaves(trace, "", &v, "--set");
+            }
+            if let Some(closed) = &closed {
+                unknown.extend(unknown_keys(closed, &v, "--set"));
+            }
+            opt.merge_mode.merge(&mut data, v);
+            if let Some(preview) = &mut preview {
+                preview.step(&format!("--set {}", entry), &data);
+            }
+            data
+        });
+
+        let mut data = data;
+        if opt.resolve_refs {
+            resolve_refs(&mut data, opt);
+            if let Some(preview) = &mut preview {
+                preview.step("--resolve-refs", &data);
+            }
+        }
+        for key in &opt.omit_key {
+            if let Some(segments) = key.split('.').map(glob_regex).collect::<Option<Vec<_>>>() {
+                omit_key(&mut data, &segments, "");
+            }
+        }
+        if let Some(preview) = preview.as_mut().filter(|_| !opt.omit_key.is_empty()) {
+            preview.step("--omit-key", &data);
+        }
+        if opt.sort_keys {
+            data = helpers::sort_keys(data);
+        }
+
+        if let Some(trace) = trace {
+            for (path, source) in trace {
+                if inspect::lookup(&data, &path).is_some() {
+                    eprintln!("{} <- {}", path, source);
+                }
+            }
+        }
+
+        if !unknown.is_empty() {
+            let message = format!("Keys not in the first value file: {}", unknown.join(", "));
+            if opt.closed_keys.as_deref() == Some("error") {
+                anyhow::bail!(message);
+            }
+            warn!("{}", message);
+        }
+        Ok(data)
+    }
+
+    /// Load the value files concurrently, up to `--jobs` at a time, in the order they are given
+    fn load_values(opt: &Opt) -> Vec<Option<Value>> {
+        let mut loaded = vec![];
+        for chunk in opt.values.chunks(Self::threads(opt)) {
+            std::thread::scope(|s| {
+                let handles: Vec<_> = chunk
+                    .iter()
+                    .map(|path| s.spawn(move || Self::load_values_source(path, opt)))
+                    .collect();
+                loaded.extend(handles.into_iter().map(|h| h.join().ok().flatten()));
+            });
+        }
+        loaded
+    }
+
+    /// Number of value files loaded or templates rendered at the same time, `--jobs` or the
+    /// number of CPUs
+    fn threads(opt: &Opt) -> usize {
+        opt.jobs
+            .filter(|jobs| *jobs > 0)
+            .or_else(|| std::thread::available_parallelism().ok().map(|n| n.get()))
+            .unwrap_or(1)
+    }
+
+    /// Load a value file or fetch values from an URL
+    fn load_values_source(path: &Path, opt: &Opt) -> Option<Value> {
+        let url = path
+            .to_str()
+            .filter(|p| p.starts_with("http://") || p.starts_with("https://"));
+        match url {
+            Some(url) => Self::fetch_values(url, opt),
+            None => Self::read_values_file(path, opt.merge_mode),
+        }
+    }
+
+    fn read_values_file(path: &Path, mode: MergeMode) -> Option<Value> {
+        if let Ok(file) = File::open(path) {
+            let ext = path.extension().unwrap_or_default().to_ascii_lowercase();
+            if ext == "gz" {
+                // The format is the extension before `.gz`
+                let inner = Path::new(path.file_stem().unwrap_or_default());
+                let ext = inner.extension().unwrap_or_default().to_ascii_lowercase();
+                let reader = BufReader::new(flate2::read::GzDecoder::new(file));
+                Self::parse_values(reader, &ext.to_string_lossy(), mode)
+            } else {
+                Self::parse_values(BufReader::new(file), &ext.to_string_lossy(), mode)
+            }
+        } else {
+            warn!("Cannot read value file {}", path.to_string_lossy());
+            None
+        }
+    }
+
+    /// Fetch values from an HTTP URL, the format is given by the content type or the extension
+    fn fetch_values(url: &str, opt: &Opt) -> Option<Value> {
+        debug!("Fetching values from {}", url);
+        let agent: ureq::Agent = ureq::Agent::config_builder()
+            .timeout_global(Some(std::time::Duration::from_secs(opt.values_timeout)))
+            .build()
+            .into();
+        let mut response = agent
+            .get(url)
+            .call()
+            .map_err(|e| format!("Cannot fetch values from {}, {}", url, e))
+            .log()
+            .ok()?;
+        let mime = response.body().mime_type().unwrap_or_default().to_ascii_lowercase();
+        let ext = if mime.contains("yaml") {
+            "yaml".to_owned()
+        } else if mime.contains("json") {
+            "json".to_owned()
+        } else if mime.contains("toml") {
+            "toml".to_owned()
+        } else {
+            let path = url.split(['?', '#']).next().unwrap_or_default();
+            let ext = Path::new(path).extension().unwrap_or_default();
+            ext.to_string_lossy().to_ascii_lowercase()
+        };
+        let content = response.body_mut().read_to_string().log().ok()?;
+        Self::parse_values(content.as_bytes(), &ext, opt.merge_mode)
+    }
+
+    /// Run a shell command and parse its standard output as values
+    fn run_values_cmd(opt: &Opt, cmd: &str) -> Option<Value> {
+        debug!("Running values command {}", cmd);
+        let output = if cfg!(windows) {
+            std::process::Command::new("cmd").args(["/C", cmd]).output()
+        } else {
+            std::process::Command::new("sh").args(["-c", cmd]).output()
+        };
+        let output = output.log().ok()?;
+        if !output.status.success() {
+            warn!("Values command {} failed with {}", cmd, output.status);
+            return None;
+        }
+        let format = opt.values_cmd_format.to_ascii_lowercase();
+        Self::parse_values(&output.stdout[..], &format, opt.merge_mode)
+    }
+
+    /// Parse values in the format given by the file extension, JSON by default
+    fn parse_values<R: Read>(mut reader: R, ext: &str, mode: MergeMode) -> Option<Value> {
+        if (ext == "yaml") || (ext == "yml") {
+            Self::read_yaml_documents(reader, mode)
+        } else if (ext == "json5") || (ext == "jsonc") {
+            let mut content = String::new();
+            reader.read_to_string(&mut content).log().ok()?;
+            json5::from_str(&content).log().ok()
+        } else if ext == "toml" {
+            let mut content = String::new();
+            reader.read_to_string(&mut content).log().ok()?;
+            toml::from_str(&content).log().ok().map(toml_to_json)
+        } else {
+            if ext != "json" {
+                // Warning
+            }
+            from_reader(reader).log().ok()
+        }
+    }
+
+    /// Read all documents of a YAML stream, multiple documents are merged in order, an empty
+    /// stream is an empty map
+    fn read_yaml_documents<R: std::io::Read>(reader: R, mode: MergeMode) -> Option<Value> {
+        let mut docs = vec![];
+        for doc in serde_yaml::Deserializer::from_reader(reader) {
+            let yaml_value = serde_yaml::Value::deserialize(doc).log().ok()?;
+            docs.push(to_value(expand_merge_keys(yaml_value)).log().unwrap_or_default());
+        }
+        if docs.len() <= 1 {
+            let doc = docs.pop().unwrap_or_default();
+            return Some(if doc.is_null() { Value::Object(Default::default()) } else { doc });
+        }
+        let mut obj = Value::Object(Default::default());
+        for (i, doc) in docs.into_iter().enumerate() {
+            if doc.is_object() {
+                mode.merge(&mut obj, doc);
+            } else {
+                warn!("YAML document {} is not a map, skipped", i);
+            }
+        }
+        Some(obj)
+    }
+
+    /// Merge the `default` and the profile sections into the root and remove them
+    fn apply_profile(obj: &mut Value, profile: &str, mode: MergeMode) {
+        let map = match obj {
+            Value::Object(m) => m,
+            _ => {
+                warn!("Value file is not a map, ignoring profile {}", profile);
+                return;
+            }
+        };
+        let default = map.remove("default");
+        let section = map.remove(profile);
+        if section.is_none() {
+            warn!("Profile {} not found in value file", profile);
+        }
+        for v in default.into_iter().chain(section) {
+            mode.merge(obj, v);
+        }
+    }
+
+    fn read_values_tree(dir: &Path) -> Value {
+        let mut tree = Value::Object(Default::default());
+        let walker = WalkDir::new(dir).follow_links(true).min_depth(1);
+        for entry in walker.into_iter().filter_map(|e| e.log().ok()) {
+            let path = entry.path();
+            if !path.is_file() {
+                continue;
+            }
+            let content = match std::fs::read(path) {
+                Ok(c) => c,
+                Err(e) => {
+                    warn!("Cannot read value file {}, {}", path.to_string_lossy(), e);
+                    continue;
+                }
+            };
+            let content = match String::from_utf8(content) {
+                Ok(s) => s,
+                Err(_) => {
+                    warn!("Skipping binary value file {}", path.to_string_lossy());
+                    continue;
+                }
+            };
+            let keys: Vec<String> = path
+                .strip_prefix(dir)
+                .unwrap_or(path)
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy().into_owned())
+                .collect();
+            debug!("Found value {}", keys.join("/"));
+            let value = keys
+                .iter()
+                .rev()
+                .fold(Value::String(content.trim().to_owned()), |v, k| {
+                    let mut m = serde_json::Map::new();
+                    m.insert(k.to_owned(), v);
+                    Value::Object(m)
+                });
+            merge(&mut tree, value);
+        }
+        tree
+    }
+
+    fn get_engine(opt: &Opt, state: &Arc<helpers::HelperState>) -> (Handlebars<'static>, Scan) {
+        let mut h = Handlebars::new();
+        h.set_strict_mode(opt.strict);
+        if !opt.no_builtin_helpers {
+            let builtins = helpers::builtin_helpers();
+            for name in &opt.disable_helper {
+                if !builtins.iter().any(|(n, _)| n == name) {
+                    warn!("Unknown built-in helper {}", name);
+                }
+            }
+            let missing = helpers::missing_features();
+            if missing.is_empty() {
+                debug!("All built-in helpers available");
+            } else {
+                let missing: Vec<_> = missing.into_iter().collect();
+                info!("Built without the helpers of the features {}", missing.join(", "));
+            }
+            let enabled = |name: &str| !opt.disable_helper.iter().any(|n| n == name);
+            helpers::register_helpers(&mut h, state, enabled);
+        }
+        for path in &opt.wasm_helper {
+            #[cfg(feature = "wasm")]
+            match wasm::WasmHelper::load(path) {
+                Ok(helper) => {
+                    info!("Registered helper {} from {}", helper.name(), path.to_string_lossy());
+                    let name = helper.name().to_owned();
+                    h.register_helper(&name, Box::new(helper));
+                }
+                Err(e) => warn!("{:#}", e),
+            }
+            #[cfg(not(feature = "wasm"))]
+            warn!("Ignoring {}, built without the `wasm` feature", path.to_string_lossy());
+        }
+        let mut scan = Scan::default();
+        if !opt.no_embedded {
+            // embedded templates come first so templates of the inputs replace them
+            let ext = opt.get_ext();
+            for (path, source) in embedded::templates() {
+                if let Some(name) = strip_suffix(&path, &ext, opt.ignore_extension_case) {
+                    let path = Path::new("<embedded>").join(&path);
+                    Self::register_template_source(&mut h, opt, name, &path, &source, &mut scan);
+                }
+            }
+        }
+        for input in &opt.input {
+            debug!("Scanning input {}", input.to_string_lossy());
+            Self::register_templates(&mut h, opt, input, &mut scan);
+        }
+        let mut sources: BTreeMap<String, PathBuf> = scan.partials.clone().into_iter().collect();
+        sources.extend(scan.templates.iter().map(|(n, t)| (n.clone(), t.source.clone())));
+        state.set_template_sources(sources);
+        (h, scan)
+    }
+
+    fn filter_file(entry: &DirEntry, suffix: &str, ignore_case: bool, names: &[String]) -> bool {
+        let path = entry.path();
+
+        // ignore vim temp files, emacs buffers and files with wrong suffix, unless listed by name
+        !path.is_file()
+            || path
+                .file_name()
+                .map(|s| {
+                    let ds = s.to_string_lossy();
+                    ds.starts_with('~')
+                        || ds.starts_with('#')
+                        || !(strip_suffix(&ds, suffix, ignore_case).is_some()
+                            || names.iter().any(|n| *n == ds))
+                })
+                .unwrap_or(true)
+    }
+
+    /// Whether the file is a template listed by `--template-name`, its name keeps any suffix
+    fn is_named_template(opt: &Opt, path: &Path) -> bool {
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        opt.template_name.iter().any(|n| *n == file_name)
+    }
+
+    /// Register all templates under `dir_path`, templates failing to parse are skipped and
+    /// their errors are collected into `scan`
+    fn register_templates<P>(
+        registry: &mut Handlebars<'static>,
+        opt: &Opt,
+        dir_path: P,
+        scan: &mut Scan,
+    ) where
+        P: AsRef<Path>,
+    {
+        if dir_path.as_ref().is_file() {
+            let tpl_name = if Self::is_named_template(opt, dir_path.as_ref()) {
+                dir_path.as_ref().file_name().unwrap_or_default().to_string_lossy()
+            } else {
+                dir_path.as_ref().file_stem().unwrap_or_default().to_string_lossy()
+            };
+            Self::register_template(registry, opt, &tpl_name, dir_path.as_ref(), scan);
+            return;
+        }
+
+        let dir_path = dir_path.as_ref();
+        let tpl_extension = &opt.get_ext();
+        let ignore_case = opt.ignore_extension_case || cfg!(any(windows, target_os = "macos"));
+        let input_root = if opt.preserve_input_root && opt.template_root.is_none() {
+            normalize_path(dir_path)
+                .file_name()
+                .map(|n| n.to_string_lossy().into_owned())
+        } else {
+            None
+        };
+
+        let prefix_len = if dir_path
+            .to_string_lossy()
+            .ends_with(['\\', '/'])
+        // `/` will work on windows too so we still need to check
+        {
+            dir_path.to_string_lossy().len()
+        } else {
+            dir_path.to_string_lossy().len() + 1
+        };
+
+        let walker = WalkDir::new(dir_path).follow_links(true);
+        let dir_iter = walker
+            .min_depth(1)
+            .into_iter()
+            .filter(|e| {
+                e.is_ok()
+                    && !Self::filter_file(
+                        e.as_ref().unwrap(),
+                        tpl_extension,
+                        ignore_case,
+                        &opt.template_name,
+                    )
+            });
+
+        for entry in dir_iter.flatten() {
+            let tpl_path = entry.path();
+            let tpl_file_path = entry.path().to_string_lossy();
+
+            let rooted = opt.template_root.as_ref().and_then(|root| {
+                let rel = normalize_path(tpl_path);
+                let rel = rel.strip_prefix(normalize_path(root)).ok()?;
+                Some(rel.to_string_lossy().into_owned())
+            });
+            let rel = match &rooted {
+                Some(rel) => rel,
+                None => tpl_file_path.get(prefix_len..).unwrap_or_default(),
+            };
+            let tpl_name = if Self::is_named_template(opt, tpl_path) {
+                Some(rel)
+            } else {
+                strip_suffix(rel, tpl_extension, ignore_case)
+            };
+            let tpl_name = match tpl_name.filter(|n| !n.is_empty()) {
+                Some(name) => name,
+                None => {
+                    warn!("Skipping {}, not ending with {}", tpl_file_path, tpl_extension);
+                    continue;
+                }
+            };
+            // replace platform path separator with our internal one
+            let tpl_canonical_name = tpl_name.replace(path::MAIN_SEPARATOR, "/");
+            // files under a `<name>.hbs.d` directory are partials namespaced by `<name>`, files
+            // under a `_partials` directory or starting with `_` are partials too
+            let bundle_suffix = format!("{}.d", tpl_extension);
+            let (dirs, file) = tpl_canonical_name
+                .rsplit_once('/')
+                .unwrap_or(("", &tpl_canonical_name));
+            if dirs.split('/').any(|d| strip_suffix(d, &bundle_suffix, ignore_case).is_some()) {
+                let partial_name = dirs
+                    .split('/')
+                    .map(|d| strip_suffix(d, &bundle_suffix, ignore_case).unwrap_or(d))
+                    .chain(std::iter::once(file))
+                    .collect::<Vec<_>>()
+                    .join("/");
+                Self::register_partial(registry, opt, &partial_name, tpl_path, scan);
+            } else if let Some(partial_name) = underscore_partial(dirs, file) {
+                Self::register_partial(registry, opt, &partial_name, tpl_path, scan);
+            } else if let Some(root) = &input_root {
+                let name = format!("{}/{}", root, tpl_canonical_name);
+                Self::register_template(registry, opt, &name, tpl_path, scan);
+            } else {
+                Self::register_template(registry, opt, &tpl_canonical_name, tpl_path, scan);
+            }
+        }
+    }
+
+    /// Read a template source, errors are collected into `scan`
+    fn read_source(opt: &Opt, name: &str, path: &Path, scan: &mut Scan) -> Option<String> {
+        let bytes = match std::fs::read(path) {
+            Ok(b) => b,
+            Err(e) => {
+                let e = handlebars::TemplateError::from((e, name.to_owned()));
+                warn!("{}", e);
+                Self::annotate(opt, path, None, &e.reason.to_string());
+                scan.errors.push(e);
+                return None;
+            }
+        };
+        let source = match (String::from_utf8(bytes), &opt.encoding) {
+            (Ok(s), _) => s,
+            (Err(e), Some(label)) => {
+                let encoding = match encoding_rs::Encoding::for_label(label.as_bytes()) {
+                    Some(encoding) => encoding,
+                    None => {
+                        warn!("Unknown encoding {}, skipping {}", label, path.to_string_lossy());
+                        return None;
+                    }
+                };
+                let (source, _, malformed) = encoding.decode(e.as_bytes());
+                if malformed {
+                    warn!("{} has bytes invalid in {}", path.to_string_lossy(), encoding.name());
+                }
+                source.into_owned()
+            }
+            (Err(e), None) => {
+                warn!(
+                    "{} is not valid UTF-8 at byte {}, skipping, use --encoding for other encodings",
+                    path.to_string_lossy(),
+                    e.utf8_error().valid_up_to()
+                );
+                return None;
+            }
+        };
+        if opt.normalize_input_eol {
+            Some(source.replace("\r\n", "\n"))
+        } else {
+            Some(source)
+        }
+    }
+
+    /// Register one partial file, it can be included by templates but doesn't generate output
+    fn register_partial(
+        registry: &mut Handlebars<'static>,
+        opt: &Opt,
+        name: &str,
+        path: &Path,
+        scan: &mut Scan,
+    ) {
+        let source = match Self::read_source(opt, name, path, scan) {
+            Some(s) => s,
+            None => return,
+        };
+        let body = helpers::resolve_auto_indent(&source);
+        match registry.register_partial(name, body).log() {
+            Ok(_) => {
+                info!("Found partial {} in {}", name, path.to_string_lossy());
+                scan.partials.insert(name.to_owned(), path.to_owned());
+            }
+            Err(e) => {
+                let location = e.line_no.zip(e.column_no).map(|l| (l, source.as_str()));
+                Self::annotate(opt, path, location, &e.reason.to_string());
+                scan.errors.push(e)
+            }
+        }
+    }
+
+    /// Register one template file, splitting its front matter
+    fn register_template(
+        registry: &mut Handlebars<'static>,
+        opt: &Opt,
+        name: &str,
+        path: &Path,
+        scan: &mut Scan,
+    ) {
+        if let Some(source) = Self::read_source(opt, name, path, scan) {
+            Self::register_template_source(registry, opt, name, path, &source, scan);
+        }
+    }
+
+    /// Register the source of a template, `path` is where it comes from
+    fn register_template_source(
+        registry: &mut Handlebars<'static>,
+        opt: &Opt,
+        name: &str,
+        path: &Path,
+        source: &str,
+        scan: &mut Scan,
+    ) {
+        let (front, body) = front_matter::split(source);
+        let body = &helpers::resolve_auto_indent(body);
+        match registry.register_template_string(name, body) {
+            Ok(_) => {
+                info!("Found template {}", path.to_string_lossy());
+                let info = TemplateInfo {
+                    source: path.to_owned(),
+                    front,
+                };
+                if scan.templates.insert(name.to_owned(), info).is_none() {
+                    scan.order.push(name.to_owned());
+                }
+            }
+            Err(e) => {
+                let location = e.line_no.zip(e.column_no);
+                let message = e.reason.to_string();
+                warn!("{}", diagnostic::report(name, &message, location, Some(body), Self::use_color(opt)));
+                Self::annotate(opt, path, location.map(|l| (l, source)), &message);
+                scan.errors.push(e)
+            }
+        }
+    }
+
+    fn get_renames(opt: &Opt) -> Vec<(Regex, String)> {
+        opt.rename
+            .iter()
+            .filter_map(|rule| {
+                let (from, to) = match rule.split_once('=') {
+                    Some(r) => r,
+                    None => {
+                        warn!("Invalid rename rule {}, expecting `<regex>=<replacement>`", rule);
+                        return None;
+                    }
+                };
+                Regex::new(&format!("^(?:{})$", from))
+                    .log()
+                    .ok()
+                    .map(|re| (re, to.to_owned()))
+            })
+            .collect()
+    }
+
+    fn get_compare_ignores(opt: &Opt) -> Vec<Regex> {
+        opt.ignore_on_compare
+            .iter()
+            .filter_map(|re| Regex::new(&format!("(?m){}", re)).log().ok())
+            .collect()
+    }
+
+    /// Whether the rendered output is the same as the existing one, ignoring the matches of
+    /// `--ignore-on-compare`
+    fn same_content(&self, existing: &[u8], rendered: &[u8]) -> bool {
+        if existing == rendered {
+            return true;
+        }
+        if self.compare_ignores.is_empty() {
+            return false;
+        }
+        let strip = |content: &[u8]| {
+            let content = String::from_utf8_lossy(content);
+            self.compare_ignores
+                .iter()
+                .fold(content.into_owned(), |s, re| re.replace_all(&s, "").into_owned())
+        };
+        strip(existing) == strip(rendered)
+    }
+
+    /// Output path of the template, the first matching rename rule is applied to the name, then
+    /// the `ext` of the front matter replaces its extension
+    fn out_path(&self, name: &str) -> PathBuf {
+        if let (Some(root), Some(out_file)) = (&self.opt.root_template, &self.opt.out_file) {
+            if root == name {
+                return out_file.clone();
+            }
+        }
+        let path = self
+            .renames
+            .iter()
+            .find(|(re, _)| re.is_match(name))
+            .map(|(re, to)| re.replace(name, to.as_str()).into_owned())
+            .unwrap_or_else(|| name.to_owned());
+        let path = self.front_ext(name, path);
+        self.opt.output.join(self.path_case(path))
+    }
+
+    /// Replace the extension of the output path of the template with the `ext` of its front
+    /// matter
+    fn front_ext(&self, name: &str, path: String) -> String {
+        let template = self.scan.templates.get(name);
+        let ext = match template.and_then(|t| t.front.ext.as_deref()) {
+            Some(ext) => ext,
+            None => return path,
+        };
+        let bare = ext.strip_prefix('.').unwrap_or(ext);
+        if bare.is_empty() || bare.contains(['/', '\\']) || bare.starts_with('.') {
+            warn!("Template {}: invalid ext {}, expecting an extension like .conf", name, ext);
+            return path;
+        }
+        let (dir, file) = match path.rsplit_once('/') {
+            Some((dir, file)) => (format!("{}/", dir), file),
+            None => (String::new(), path.as_str()),
+        };
+        let stem = match file.rsplit_once('.') {
+            Some((stem, _)) if !stem.is_empty() => stem,
+            _ => file,
+        };
+        format!("{}{}.{}", dir, stem, bare)
+    }
+
+    /// Lowercase an output path relative to the output directory with `--lowercase-paths`
+    fn path_case(&self, path: String) -> String {
+        if self.opt.lowercase_paths {
+            path.to_lowercase()
+        } else {
+            path
+        }
+    }
+
+    fn context(&self) -> &Value {
+        match &self.opt.context_root {
+            Some(key) => match self.data.get(key) {
+                Some(v) => v,
+                None => {
+                    self.missing_context_root.call_once(|| {
+                        warn!("Context root {} not found, using the whole data", key);
+                    });
+                    &self.data
+                }
+            },
+            None => &self.data,
+        }
+    }
+
+    /// Add the reserved template metadata variable to a copy of the data
+    fn render_data(&self, data: &Value, name: &str, out_path: &Path) -> Value {
+        let flatten_data = self.scan.templates.get(name).is_some_and(|t| t.front.flatten_data);
+        let mut data = if flatten_data && data.is_object() {
+            let mut flat = serde_json::Map::new();
+            flatten(data, "", &mut flat);
+            Value::Object(flat)
+        } else {
+            data.clone()
+        };
+        if let Value::Object(m) = &mut data {
+            let mut meta = serde_json::Map::new();
+            meta.insert("name".to_owned(), Value::String(name.to_owned()));
+            meta.insert(
+                "output_path".to_owned(),
+                Value::String(out_path.to_string_lossy().into_owned()),
+            );
+            m.insert(self.opt.template_var.clone(), Value::Object(meta));
+            if !self.opt.opt_var.is_empty() {
+                m.insert(self.opt.opt_var.clone(), self.opt_meta());
+            }
+            if let (Some(key), Some(env)) = (&self.opt.expose_env, &self.env) {
+                m.insert(key.clone(), env.clone());
+            }
+        }
+        data
+    }
+
+    /// Options exposed to templates, commands, inline values and logging options are left out
+    fn opt_meta(&self) -> Value {
+        let paths = |paths: &[PathBuf]| -> Vec<String> {
+            paths.iter().map(|p| p.to_string_lossy().into_owned()).collect()
+        };
+        serde_json::json!({
+            "output": self.opt.output.to_string_lossy(),
+            "input": paths(&self.opt.input),
+            "extension": self.opt.get_ext(),
+            "profile": self.opt.profile,
+            "values": paths(&self.opt.values),
+            "values_tree": self.opt.values_tree.as_ref().map(|p| p.to_string_lossy()),
+            "root_template": self.opt.root_template,
+        })
+    }
+
+    fn progress_bar(&self, len: usize) -> ProgressBar {
+        if !self.opt.progress || self.opt.quiet || !std::io::stderr().is_terminal() {
+            return ProgressBar::hidden();
+        }
+        let pb = ProgressBar::new(len as u64);
+        if let Ok(style) = ProgressStyle::with_template("{bar:40} {pos}/{len} {msg}") {
+            pb.set_style(style);
+        }
+        pb
+    }
+
+    /// Names of the registered templates matching `--include`, ordered by their front matter
+    /// `weight`, then by name unless `--no-sort`, only the root template with `--root-template`
+    ///
+    /// Templates are rendered in this order, which is the order of the `--concat` parts and of
+    /// the `--index`.
+    fn template_names(&self) -> Vec<&String> {
+        if let Some(root) = &self.opt.root_template {
+            return match self.scan.templates.get_key_value(root) {
+                Some((name, _)) => vec![name],
+                None => {
+                    warn!("Root template {} not found", root);
+                    vec![]
+                }
+            };
+        }
+        let include: Vec<Regex> = self.opt.include.iter().filter_map(|g| glob_regex(g)).collect();
+        let mut names: Vec<&String> = self
+            .scan
+            .order
+            .iter()
+            .filter(|name| self.scan.templates.contains_key(*name))
+            .filter(|name| include.is_empty() || include.iter().any(|re| re.is_match(name)))
+            .filter(|name| self.affected.as_ref().is_none_or(|a| a.contains(*name)))
+            .collect();
+        let weight = |name: &String| self.scan.templates[name].front.weight;
+        if self.opt.no_sort {
+            names.sort_by_key(|name| weight(name));
+        } else {
+            names.sort_by_key(|name| (weight(name), *name));
+        }
+        names
+    }
+
+    /// Render all templates without writing anything, returns the number of failed templates
+    pub fn check(&self) -> usize {
+        let data = self.context();
+        let mut failed = self.scan.errors.len();
+        for name in self.template_names() {
+            let result = self
+                .jobs(name, data)
+                .and_then(|jobs| {
+                    jobs.iter()
+                        .try_for_each(|job| self.render_to(name, job, std::io::sink()))
+                });
+            match result {
+                Ok(_) => info!("Template {} OK", name),
+                Err(e) => {
+                    error!("Template {}: {}", name, e);
+                    self.annotate_failure(name, &e.into());
+                    failed += 1;
+                }
+            }
+        }
+        failed
+    }
+
+    /// Render all templates and compare them with the existing outputs, returns the number of
+    /// outputs missing, different or failing to render
+    pub fn verify(&self) -> Result<usize> {
+        let mut outdated = 0;
+        self.for_each_job(self.template_names().into_iter(), |name, job| {
+            let mut buf = vec![];
+            self.render_to(name, job, &mut buf)?;
+            // the split files are checked like `write_or_split` writes them
+            match self.split_outputs(name, job, &buf) {
+                Some(outputs) => {
+                    for (job, content) in outputs {
+                        outdated += usize::from(!self.verify_output(&job.out_path, content));
+                    }
+                }
+                None => outdated += usize::from(!self.verify_output(&job.out_path, buf)),
+            }
+            Ok(())
+        })?;
+        Ok(outdated + self.summary.lock().unwrap().failed)
+    }
+
+    /// Whether the output file has the rendered content, logging it when it's outdated or
+    /// missing and printing the difference with `--diff`
+    fn verify_output(&self, out_path: &Path, buf: Vec<u8>) -> bool {
+        let path = out_path.to_string_lossy();
+        let buf = match self.with_region(out_path, buf) {
+            Some(buf) => buf,
+            None => return true,
+        };
+        match std::fs::read(out_path) {
+            Ok(existing) if self.same_content(&existing, &buf) => {
+                info!("{} is up to date", path);
+                true
+            }
+            Ok(existing) => {
+                error!("{} is outdated", path);
+                if self.opt.diff {
+                    let old = String::from_utf8_lossy(&existing);
+                    let new = String::from_utf8_lossy(&buf);
+                    let diff = similar::TextDiff::from_lines(&old, &new);
+                    print!("{}", diff.unified_diff().header(&path, &path));
+                }
+                false
+            }
+            Err(_) => {
+                error!("{} is missing", path);
+                false
+            }
+        }
+    }
+
+    /// Warn about variables referenced by the template but missing in the data
+    fn report_missing(&self, name: &str, data: &Value) {
+        let template = match self.engine.get_template(name) {
+            Some(t) => t,
+            None => return,
+        };
+        let helpers: HashSet<&str> = helpers::builtin_helpers()
+            .into_iter()
+            .map(|(name, _)| name)
+            .chain(self.custom_helpers.iter().map(String::as_str))
+            .collect();
+        let missing: Vec<String> = inspect::referenced_paths(template, |n| helpers.contains(n))
+            .into_iter()
+            .filter(|p| inspect::lookup(data, p).is_none())
+            .collect();
+        if !missing.is_empty() {
+            warn!("Template {} references missing variables: {}", name, missing.join(", "));
+        }
+    }
+
+    /// Write the data paths, helpers and partials referenced by each template as JSON, `-` for
+    /// stdout, without rendering anything
+    pub fn write_vars_report(&self, path: &Path) -> Result<()> {
+        let helpers: HashSet<&str> = helpers::builtin_helpers()
+            .into_iter()
+            .map(|(name, _)| name)
+            .chain(self.custom_helpers.iter().map(String::as_str))
+            .chain(inspect::HANDLEBARS_HELPERS.iter().copied())
+            .collect();
+        let mut report = BTreeMap::new();
+        for name in self.template_names() {
+            let template = match self.engine.get_template(name) {
+                Some(t) => t,
+                None => continue,
+            };
+            let is_helper = |n: &str| helpers.contains(n);
+            report.insert(
+                name,
+                serde_json::json!({
+                    "variables": inspect::referenced_paths(template, is_helper),
+                    "helpers": inspect::referenced_helpers(template, is_helper),
+                    "partials": inspect::included_partials(template),
+                }),
+            );
+        }
+        let json = serde_json::to_string_pretty(&report)?;
+        if path == Path::new("-") {
+            println!("{}", json);
+        } else {
+            std::fs::write(path, json + "\n")
+                .with_context(|| format!("Failed to write {}", path.to_string_lossy()))?;
+        }
+        Ok(())
+    }
+
+    /// Print the changes of the values after each source in precedence order, then the merged
+    /// values, without registering or rendering any template
+    pub fn preview_merge(opt: &Opt) -> Result<()> {
+        let data = Self::get_data(opt)?;
+        println!("==> merged values <==");
+        println!("{}", serde_json::to_string_pretty(&data)?);
+        Ok(())
+    }
+
+    /// Write the values flattened into a Java properties file, `-` for stdout, without
+    /// rendering anything
+    pub fn write_properties(&self, path: &Path) -> Result<()> {
+        let properties = to_properties(&self.data);
+        if path == Path::new("-") {
+            print!("{}", properties);
+        } else {
+            info!("Values as properties => {}", path.to_string_lossy());
+            std::fs::write(path, properties)
+                .with_context(|| format!("Failed to write {}", path.to_string_lossy()))?;
+        }
+        Ok(())
+    }
+
+    /// Latest modification time of the value sources
+    fn data_mtime(&self) -> Option<SystemTime> {
+        let files = self.opt.values.iter().cloned().chain(
+            self.opt
+                .values_tree
+                .iter()
+                .flat_map(|dir| WalkDir::new(dir).follow_links(true))
+                .filter_map(|e| e.ok())
+                .map(|e| e.into_path()),
+        );
+        files.filter_map(|p| mtime(&p)).max()
+    }
+
+    /// Check if the output is newer than the template source and the value sources
+    fn up_to_date(&self, name: &str, out_path: &Path, data_mtime: Option<SystemTime>) -> bool {
+        let out_mtime = match mtime(out_path) {
+            Some(t) => t,
+            None => return false,
+        };
+        let src_mtime = self.scan.templates.get(name).and_then(|t| mtime(&t.source));
+        match src_mtime.into_iter().chain(data_mtime).max() {
+            Some(t) => t < out_mtime,
+            None => false,
+        }
+    }
+
+    /// Evaluate the `when` condition in the front matter of the template
+    fn should_generate(&self, name: &str, data: &Value) -> bool {
+        let when = match self.scan.templates.get(name).and_then(|t| t.front.when.as_ref()) {
+            Some(w) => w,
+            None => return true,
+        };
+        let result = if when.contains("{{") {
+            match self.engine.render_template(when, data).log() {
+                Ok(s) => {
+                    let s = s.trim();
+                    !(s.is_empty() || s == "false" || s == "0")
+                }
+                Err(_) => false,
+            }
+        } else {
+            inspect::lookup(data, when).map(is_truthy).unwrap_or(false)
+        };
+        if !result {
+            info!("Skipping template {}, condition `{}` is false", name, when);
+        }
+        result
+    }
+
+    /// The `requires` paths of the template that are missing from the data or empty
+    fn missing_requirements<'a>(&'a self, name: &str, data: &Value) -> Vec<&'a str> {
+        let requires = match self.scan.templates.get(name) {
+            Some(t) => &t.front.requires,
+            None => return vec![],
+        };
+        requires
+            .iter()
+            .filter(|path| match inspect::lookup(data, path) {
+                None | Some(Value::Null) => true,
+                Some(Value::String(s)) => s.is_empty(),
+                Some(Value::Array(a)) => a.is_empty(),
+                Some(Value::Object(m)) => m.is_empty(),
+                Some(_) => false,
+            })
+            .map(String::as_str)
+            .collect()
+    }
+
+    /// Outputs of the template, one for most templates, or one per item with `forEach`
+    fn jobs(&self, name: &str, data: &Value) -> Result<Vec<Job>, RenderError> {
+        let front = self.scan.templates.get(name).map(|t| &t.front);
+        let key = match front.and_then(|f| f.for_each.as_ref()) {
+            Some(key) => key,
+            None => {
+                let out_path = self.out_path(name);
+                let data = self.render_data(data, name, &out_path);
+                return Ok(vec![Job {
+                    out_path,
+                    data,
+                    index: None,
+                }]);
+            }
+        };
+        let items = match inspect::lookup(data, key) {
+            Some(Value::Array(items)) => items,
+            Some(_) => {
+                warn!("Template {}: forEach {} is not an array", name, key);
+                return Ok(vec![]);
+            }
+            None => {
+                info!("Template {}: forEach {} not found", name, key);
+                return Ok(vec![]);
+            }
+        };
+        let output = match front.and_then(|f| f.output.as_ref()) {
+            Some(output) => Template::compile(output).map_err(|e| {
+                RenderError::new(format!("Template {}: invalid output path, {}", name, e))
+            })?,
+            None => {
+                return Err(RenderError::new(format!(
+                    "Template {}: forEach requires an output path in the front matter",
+                    name
+                )))
+            }
+        };
+        let chunks: Vec<Value>;
+        let items = match front.and_then(|f| f.chunk_size) {
+            Some(0) => {
+                return Err(RenderError::new(format!(
+                    "Template {}: chunkSize must be at least 1",
+                    name
+                )))
+            }
+            Some(size) => {
+                chunks = items.chunks(size).map(|c| Value::Array(c.to_vec())).collect();
+                &chunks
+            }
+            None => items,
+        };
+        items
+            .iter()
+            .enumerate()
+            .map(|(index, item)| {
+                let mut path = StringOutput::new();
+                let job = Job {
+                    out_path: PathBuf::new(),
+                    data: item.clone(),
+                    index: Some(index),
+                };
+                self.render_template_to(&output, &job, &mut path)?;
+                let path = path.into_string().map_err(|e| RenderError::new(e.to_string()))?;
+                let out_path = self.opt.output.join(self.path_case(path.trim().to_owned()));
+                self.check_safe_path(&out_path)
+                    .map_err(|e| RenderError::new(e.to_string()))?;
+                Ok(Job {
+                    data: self.render_data(item, name, &out_path),
+                    out_path,
+                    index: Some(index),
+                })
+            })
+            .collect()
+    }
+
+    fn render_template_to(
+        &self,
+        template: &Template,
+        job: &Job,
+        out: &mut dyn Output,
+    ) -> Result<(), RenderError> {
+        let ctx = handlebars::Context::wraps(&job.data)?;
+        let mut rc = RenderContext::new(template.name.as_ref());
+        if let (Some(index), Some(block)) = (job.index, rc.block_mut()) {
+            block.set_local_var("index", Value::from(index));
+        }
+        template.render(&self.engine, &ctx, &mut rc, out)
+    }
+
+    /// Render the banner as a comment for the output, `None` for file types skipped by
+    /// `--banner-skip`
+    fn banner(&self, job: &Job) -> Result<Option<String>, RenderError> {
+        let banner = match &self.banner {
+            Some(banner) => banner,
+            None => return Ok(None),
+        };
+        let ext = job.out_path.extension().unwrap_or_default().to_string_lossy();
+        let skipped = self.opt.banner_skip.iter();
+        if skipped.map(|s| s.trim_start_matches('.')).any(|s| s.eq_ignore_ascii_case(&ext)) {
+            return Ok(None);
+        }
+        let mut text = StringOutput::new();
+        self.render_template_to(banner, job, &mut text)?;
+        let text = text.into_string().map_err(|e| RenderError::new(e.to_string()))?;
+        let (start, end) = match self.opt.comment_style.as_str() {
+            "<!-- -->" => ("<!--", " -->"),
+            style => (style, ""),
+        };
+        let mut comment = String::new();
+        for line in text.trim_end().lines() {
+            if line.is_empty() {
+                comment.push_str(&format!("{}{}\n", start, end));
+            } else {
+                comment.push_str(&format!("{} {}{}\n", start, line, end));
+            }
+        }
+        Ok(Some(comment))
+    }
+
+    /// Render the template for one output, then report the variables it references missing in
+    /// the data with `--report-missing`, whether the output is written, checked or printed
+    fn render_to<W: Write>(&self, name: &str, job: &Job, w: W) -> Result<(), RenderError> {
+        self.render_output_to(name, job, w)?;
+        if self.opt.report_missing {
+            self.report_missing(name, &job.data);
+        }
+        Ok(())
+    }
+
+    /// Render the template for one output, with the banner, the validation and reformatting
+    fn render_output_to<W: Write>(&self, name: &str, job: &Job, w: W) -> Result<(), RenderError> {
+        let limit = self.opt.max_render_size.as_deref().and_then(helpers::parse_bytes);
+        let mut w = LimitedWriter {
+            inner: w,
+            limit: limit.unwrap_or(u64::MAX),
+            written: 0,
+        };
+        let template = self
+            .engine
+            .get_template(name)
+            .ok_or_else(|| RenderError::new(format!("Template not found: {}", name)))?;
+        let banner = self.banner(job)?;
+        let ext = job.out_path.extension().unwrap_or_default().to_ascii_lowercase();
+        let reformat_as = self.opt.reformat.as_deref().filter(|f| match *f {
+            "yaml" => ext == "yaml" || ext == "yml",
+            _ => ext == "json",
+        });
+        let validate_as = match ext.to_str() {
+            _ if !self.opt.validate_output => None,
+            Some("json") => Some("json"),
+            Some("yaml" | "yml") => Some("yaml"),
+            Some("toml") => Some("toml"),
+            _ => None,
+        };
+        if banner.is_none() && reformat_as.is_none() && validate_as.is_none() {
+            return self.render_template_to(template, job, &mut WriteOutput(w));
+        }
+        let mut out = StringOutput::new();
+        self.render_template_to(template, job, &mut out)?;
+        let text = out.into_string().map_err(|e| RenderError::new(e.to_string()))?;
+        // The banner and reformatting go after a shebang line so scripts stay executable
+        let (shebang, text) = split_shebang(&text);
+        w.write_all(shebang.as_bytes())?;
+        if let Some(banner) = banner {
+            if !shebang.is_empty() && !shebang.ends_with('\n') {
+                w.write_all(b"\n")?;
+            }
+            w.write_all(banner.as_bytes())?;
+        }
+        if let Some(format) = validate_as {
+            validate(text, format).map_err(|e| {
+                RenderError::new(format!(
+                    "Output {} is not valid {}, {}",
+                    job.out_path.to_string_lossy(),
+                    format.to_uppercase(),
+                    e
+                ))
+            })?;
+        }
+        let text = match reformat_as {
+            Some(format) => reformat(text, format).unwrap_or_else(|| {
+                warn!("Cannot reformat {}, writing it as rendered", name);
+                text.to_owned()
+            }),
+            None => text.to_owned(),
+        };
+        w.write_all(text.as_bytes())?;
+        Ok(())
+    }
+
+    /// Record the template and the digest of the output in extended attributes with `--xattr`,
+    /// failures like on file systems without extended attributes are warnings
+    fn tag_output(&self, name: &str, out_path: &Path, content: &[u8]) {
+        if !self.opt.xattr {
+            return;
+        }
+        let template = format!("{}{}", name, self.opt.get_ext());
+        if let Err(e) = set_xattrs(out_path, &template, content) {
+            warn!("Cannot set extended attributes of {}, {}", out_path.to_string_lossy(), e);
+        }
+    }
+
+    /// Permissions of the output from the front matter or `--chmod`
+    ///
+    /// Symbolic modes in the front matter apply to `--chmod`, or to the permissions the output
+    /// was written with.
+    fn mode(&self, name: &str, out_path: &Path) -> Option<u32> {
+        let default = self.opt.chmod.as_deref().and_then(parse_mode);
+        let default = default.or_else(|| self.source_exec_mode(name));
+        let front = self.scan.templates.get(name).and_then(|t| t.front.mode.as_deref());
+        let m = match front {
+            Some(m) => m,
+            None => return default,
+        };
+        if let Some(mode) = parse_mode(m) {
+            return Some(mode);
+        }
+        if !m.trim().starts_with(|c: char| c.is_ascii_digit()) {
+            if let Some(base) = default.or_else(|| file_mode(out_path)) {
+                if let Some(mode) = apply_symbolic_mode(base, m) {
+                    return Some(mode);
+                }
+            } else if cfg!(not(unix)) {
+                warn!("Template {}: ignoring mode {}, only supported on Unix", name, m);
+                return None;
+            }
+        }
+        warn!("Template {}: invalid mode {}, expecting permissions like 0600 or u+x", name, m);
+        default
+    }
+
+    /// Permissions of the template source for its output, `None` when the source is not
+    /// executable or with `--no-preserve-mode`
+    fn source_exec_mode(&self, name: &str) -> Option<u32> {
+        if self.opt.no_preserve_mode {
+            return None;
+        }
+        let source = &self.scan.templates.get(name)?.source;
+        let mode = file_mode(source)? & 0o7777;
+        if mode & 0o111 == 0 {
+            return None;
+        }
+        Some(mode)
+    }
+
+    /// Splice the rendered output into the region of the existing output with `--region`,
+    /// `None` if the output should be left untouched
+    fn with_region(&self, out_path: &Path, buf: Vec<u8>) -> Option<Vec<u8>> {
+        if !self.opt.region {
+            return Some(buf);
+        }
+        let existing = match std::fs::read_to_string(out_path) {
+            Ok(existing) => existing,
+            Err(_) => return Some(buf),
+        };
+        let content = String::from_utf8_lossy(&buf);
+        match splice_region(&existing, &content, &self.opt.region_start, &self.opt.region_end) {
+            Some(spliced) => Some(spliced.into_bytes()),
+            None if self.opt.region_fallback => {
+                info!("No region in {}, writing the whole file", out_path.to_string_lossy());
+                Some(buf)
+            }
+            None => {
+                warn!("No region in {}, left untouched", out_path.to_string_lossy());
+                None
+            }
+        }
+    }
+
+    fn generate_one(&self, name: &str, job: &Job, data_mtime: Option<SystemTime>) -> Result<()> {
+        if self.skip_up_to_date(name, &job.out_path, data_mtime) {
+            return Ok(());
+        }
+        let input = self.input_digest(name, job);
+        if input.as_deref().is_some_and(|input| self.cache_hit(&job.out_path, input)) {
+            info!("{} is cached", job.out_path.to_string_lossy());
+            let mut summary = self.summary.lock().unwrap();
+            summary.skipped += 1;
+            summary.output(name, &job.out_path);
+            return Ok(());
+        }
+        let mut buf = vec![];
+        self.render_to(name, job, &mut buf)?;
+        // digest of the rendered output, the file may still be staged or split by now
+        let output = input.is_some().then(|| hex_digest("sha256", &buf));
+        self.write_or_split(name, job, buf)?;
+        if let (Some(input), Some(output)) = (input, output) {
+            let entry = CacheEntry { input, output };
+            let key = self.state_key(&job.out_path);
+            self.cache.lock().unwrap().insert(key, entry);
+        }
+        Ok(())
+    }
+
+    /// Digest of the template with the partials it includes, the data of the job and the
+    /// options, `None` without `--cache-file` or when a source can't be read
+    fn input_digest(&self, name: &str, job: &Job) -> Option<String> {
+        use sha2::Digest;
+        if self.opt.cache_file.is_none() || self.opt.split_marker.is_some() {
+            return None;
+        }
+        let mut hasher = sha2::Sha256::new();
+        let mut seen = BTreeSet::new();
+        let mut todo = vec![name.to_owned()];
+        while let Some(name) = todo.pop() {
+            let source = self.scan.templates.get(&name).map(|t| &t.source);
+            let source = source.or_else(|| self.scan.partials.get(&name))?;
+            hasher.update(format!("{}\0", name));
+            hasher.update(std::fs::read(source).ok()?);
+            if let Some(t) = self.engine.get_template(&name) {
+                let partials = inspect::included_partials(t).into_iter();
+                todo.extend(partials.filter(|p| seen.insert(p.clone())));
+            }
+        }
+        hasher.update(serde_json::to_vec(&job.data).ok()?);
+        let mut opt = to_value(&self.opt).ok()?;
+        if let Value::Object(m) = &mut opt {
+            for key in ["no-cache", "verbose", "quiet", "log-level", "log-format"] {
+                m.remove(key);
+            }
+        }
+        hasher.update(serde_json::to_vec(&opt).ok()?);
+        Some(hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect())
+    }
+
+    /// Whether the output was rendered from the same inputs by the last run and is unchanged
+    fn cache_hit(&self, out_path: &Path, input: &str) -> bool {
+        let cache = self.cache.lock().unwrap();
+        let entry = match cache.get(&self.state_key(out_path)) {
+            Some(entry) if entry.input == input => entry,
+            _ => return false,
+        };
+        std::fs::read(out_path).is_ok_and(|content| hex_digest("sha256", &content) == entry.output)
+    }
+
+    /// Write the output, or each file of it delimited by `--split-marker` lines
+    fn write_or_split(&self, name: &str, job: &Job, buf: Vec<u8>) -> Result<()> {
+        match self.split_outputs(name, job, &buf) {
+            Some(outputs) => {
+                for (job, content) in outputs {
+                    self.write_output(name, &job, content)?;
+                }
+                Ok(())
+            }
+            None => self.write_output(name, job, buf),
+        }
+    }
+
+    /// Files of the output delimited by `--split-marker` lines, without the duplicate ones and
+    /// the ones escaping the output directory, `None` if the output is not split
+    fn split_outputs(&self, name: &str, job: &Job, buf: &[u8]) -> Option<Vec<(Job, Vec<u8>)>> {
+        let marker = self.opt.split_marker.as_deref()?;
+        let text = String::from_utf8_lossy(buf);
+        let mut seen = HashSet::new();
+        let mut outputs = vec![];
+        for (path, content) in split_files(&text, marker)? {
+            if !seen.insert(path) {
+                warn!("Template {}: duplicate split file {}, skipping", name, path);
+                continue;
+            }
+            let job = Job {
+                out_path: self.opt.output.join(path),
+                data: job.data.clone(),
+                index: job.index,
+            };
+            if !self.escapes_output(name, &job) {
+                outputs.push((job, content.as_bytes().to_vec()));
+            }
+        }
+        Some(outputs)
+    }
+
+    /// Whether the output is skipped by `--incremental` because it's newer than its inputs
+    fn skip_up_to_date(&self, name: &str, out_path: &Path, data_mtime: Option<SystemTime>) -> bool {
+        if self.opt.incremental && self.up_to_date(name, out_path, data_mtime) {
+            info!("{} is up to date", out_path.to_string_lossy());
+            let mut summary = self.summary.lock().unwrap();
+            summary.skipped += 1;
+            summary.output(name, out_path);
+            return true;
+        }
+        false
+    }
+
+    /// Why an existing output should not be written, symlinks unless `--follow-symlinks` and
+    /// anything but regular files, like pipes and devices, unless `--allow-special`
+    fn refuse_target(&self, out_path: &Path) -> Option<&'static str> {
+        let meta = std::fs::symlink_metadata(out_path).ok()?;
+        if meta.file_type().is_symlink() && !self.opt.follow_symlinks {
+            return Some("it is a symlink, use --follow-symlinks to write through it");
+        }
+        let meta = std::fs::metadata(out_path).ok()?;
+        if !meta.is_file() && !self.opt.allow_special {
+            return Some("it is not a regular file, use --allow-special to write it anyway");
+        }
+        None
+    }
+
+    /// Write a rendered output into the output directory
+    fn write_output(&self, name: &str, job: &Job, buf: Vec<u8>) -> Result<()> {
+        let out_path = &job.out_path;
+        info!("{}{} => {}", name, self.opt.get_ext(), out_path.to_string_lossy());
+        if let Some(reason) = self.refuse_target(out_path) {
+            warn!("Not writing {}, {}", out_path.to_string_lossy(), reason);
+            self.summary.lock().unwrap().skipped += 1;
+            return Ok(());
+        }
+        let buf = match self.with_region(out_path, buf) {
+            Some(buf) => buf,
+            None => {
+                self.summary.lock().unwrap().skipped += 1;
+                return Ok(());
+            }
+        };
+        if let Some(path) = out_path.parent().filter(|_| self.staging.is_none()) {
+            std::fs::create_dir_all(path).log().ok();
+        };
+        let existing = std::fs::read(out_path).ok();
+        if existing.as_ref().is_some_and(|existing| self.same_content(existing, &buf)) {
+            info!("{} is unchanged", out_path.to_string_lossy());
+            self.set_attributes(name, out_path, out_path)?;
+            self.record_state(out_path, &buf);
+            self.tag_output(name, out_path, &buf);
+            let mut summary = self.summary.lock().unwrap();
+            summary.skipped += 1;
+            summary.output(name, out_path);
+            return Ok(());
+        }
+        if existing.is_some() && !self.opt.force && self.edited(out_path) {
+            warn!(
+                "Not writing {}, it was edited since the last run, use --force to overwrite it",
+                out_path.to_string_lossy()
+            );
+            self.summary.lock().unwrap().skipped += 1;
+            return Ok(());
+        }
+        let existed = existing.is_some();
+        self.record_state(out_path, &buf);
+        let target = self.stage(out_path);
+        if let Some(path) = target.parent().filter(|_| target != *out_path) {
+            std::fs::create_dir_all(path).log().ok();
+        }
+        std::fs::write(&target, &buf).with_context(|| {
+            format!("Failed to write output file {}", out_path.to_string_lossy())
+        })?;
+        self.set_attributes(name, out_path, &target)?;
+        self.tag_output(name, &target, &buf);
+        let mut summary = self.summary.lock().unwrap();
+        summary.record(existed, buf.len());
+        summary.output(name, out_path);
+        Ok(())
+    }
+
+    /// Set the mode and modification time of the output written at the target, also when its
+    /// content was unchanged
+    fn set_attributes(&self, name: &str, out_path: &Path, target: &Path) -> Result<()> {
+        if let Some(mode) = self.mode(name, target) {
+            set_mode(target, mode).with_context(|| {
+                format!("Failed to set the mode of {}", out_path.to_string_lossy())
+            })?;
+        }
+        if let Some(mtime) = self.opt.mtime {
+            let mtime = filetime::FileTime::from_unix_time(mtime, 0);
+            filetime::set_file_mtime(target, mtime).with_context(|| {
+                format!("Failed to set the modification time of {}", out_path.to_string_lossy())
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Staging directory of `--transactional`, next to the output directory so the outputs can
+    /// be renamed into place
+    fn staging_dir(&self) -> PathBuf {
+        let output = normalize_path(&self.opt.output);
+        let name = output.file_name().unwrap_or_default().to_string_lossy();
+        let staging = format!(".{}.tplgen-{}", name, std::process::id());
+        output.parent().unwrap_or(&output).join(staging)
+    }
+
+    /// Path the output is written to, in the staging directory with `--transactional`
+    fn stage(&self, out_path: &Path) -> PathBuf {
+        let staging = match &self.staging {
+            Some(staging) => staging,
+            None => return out_path.to_owned(),
+        };
+        let mut staged = self.staged.lock().unwrap();
+        let path = staging.join("new").join(staged.len().to_string());
+        staged.push((path.clone(), out_path.to_owned()));
+        path
+    }
+
+    /// Move the outputs staged by `--transactional` into place if all templates succeeded,
+    /// the staging directory is removed either way
+    fn commit_staged(&self, result: Result<()>) -> Result<()> {
+        let staging = match &self.staging {
+            Some(staging) => staging,
+            None => return result,
+        };
+        let staged = std::mem::take(&mut *self.staged.lock().unwrap());
+        let failed = self.failed();
+        let result = result.and_then(|_| match failed {
+            0 => self.move_staged(staging, &staged),
+            _ => Err(anyhow::anyhow!("{} template(s) failed", failed)),
+        });
+        if staging.exists() {
+            std::fs::remove_dir_all(staging).log().ok();
+        }
+        result.context("Output directory left untouched")
+    }
+
+    /// Move the staged outputs into place, the outputs they replace are kept in the staging
+    /// directory until all the moves succeeded, and moved back otherwise
+    fn move_staged(&self, staging: &Path, staged: &[(PathBuf, PathBuf)]) -> Result<()> {
+        let backup = staging.join("backup");
+        std::fs::create_dir_all(&backup)?;
+        let mut done: Vec<(&Path, Option<PathBuf>)> = vec![];
+        let mut result = Ok(());
+        for (i, (from, to)) in staged.iter().enumerate() {
+            let mut saved = None;
+            if to.exists() {
+                let path = backup.join(i.to_string());
+                if let Err(e) = move_file(to, &path) {
+                    let to = to.to_string_lossy();
+                    result = Err(e).context(format!("Failed to replace {}", to));
+                    break;
+                }
+                saved = Some(path);
+            }
+            done.push((to, saved));
+            if let Some(parent) = to.parent() {
+                std::fs::create_dir_all(parent).log().ok();
+            }
+            if let Err(e) = move_file(from, to) {
+                let to = to.to_string_lossy();
+                result = Err(e).context(format!("Failed to move {} into place", to));
+                break;
+            }
+            info!("Moved {} into place", to.to_string_lossy());
+        }
+        if result.is_err() {
+            for (to, saved) in done.into_iter().rev() {
+                std::fs::remove_file(to).ok();
+                if let Some(saved) = saved {
+                    move_file(&saved, to).log().ok();
+                }
+            }
+            warn!("Restored the outputs replaced before the failure");
+            return result;
+        }
+        info!("Moved {} staged output(s) into place", staged.len());
+        Ok(())
+    }
+
+    /// Colorize error reports when logging in human format to a terminal, unless `NO_COLOR`
+    fn use_color(opt: &Opt) -> bool {
+        opt.log_format == LogFormat::Human
+            && std::env::var_os("NO_COLOR").is_none()
+            && std::io::stderr().is_terminal()
+    }
+
+    /// The template actually failing, the error message and its location
+    fn failure<'a>(
+        &self,
+        name: &'a str,
+        e: &'a anyhow::Error,
+    ) -> (&'a str, String, Option<(usize, usize)>) {
+        let render_error = e.chain().find_map(|c| c.downcast_ref::<RenderError>());
+        let location = render_error.and_then(|re| re.line_no.zip(re.column_no));
+        match (render_error, location) {
+            (Some(re), Some(_)) => {
+                let mut message = re.desc.clone();
+                if let Some(cause) = std::error::Error::source(re) {
+                    message = format!("{}, {}", message, cause);
+                }
+                (re.template_name.as_deref().unwrap_or(name), message, location)
+            }
+            _ => (name, format!("{:#}", e), None),
+        }
+    }
+
+    /// Source file of a template or partial
+    fn source_path(&self, name: &str) -> Option<&PathBuf> {
+        let template = self.scan.templates.get(name).map(|t| &t.source);
+        template.or_else(|| self.scan.partials.get(name))
+    }
+
+    /// Describe a failure of a template, with the failing line of the source for render errors
+    fn error_report(&self, name: &str, e: &anyhow::Error) -> String {
+        let (name, message, location) = self.failure(name, e);
+        let source = location
+            .and_then(|_| self.source_path(name))
+            .and_then(|path| std::fs::read_to_string(path).ok());
+        let body = source.as_deref().map(|s| front_matter::split(s).1);
+        diagnostic::report(name, &message, location, body, Self::use_color(&self.opt))
+    }
+
+    /// Annotate the source of the failing template with `--error-format github`
+    fn annotate_failure(&self, name: &str, e: &anyhow::Error) {
+        if self.opt.error_format == ErrorFormat::Human {
+            return;
+        }
+        let (name, message, location) = self.failure(name, e);
+        let path = match self.source_path(name) {
+            Some(path) => path,
+            None => return,
+        };
+        let source = location.and_then(|_| std::fs::read_to_string(path).ok());
+        let location = location.zip(source.as_deref());
+        Self::annotate(&self.opt, path, location, &message);
+    }
+
+    /// Print an error as a GitHub Actions annotation with `--error-format github`
+    ///
+    /// The location is relative to the template body, it's moved past the front matter of the
+    /// source to point at the line in the file.
+    fn annotate(opt: &Opt, path: &Path, location: Option<((usize, usize), &str)>, message: &str) {
+        if opt.error_format == ErrorFormat::Human {
+            return;
+        }
+        let location = location.map(|((line, column), source)| {
+            let body = front_matter::split(source).1;
+            let front = source[..source.len() - body.len()].matches('\n').count();
+            (line + front, column)
+        });
+        let file = path.to_string_lossy();
+        println!("{}", diagnostic::github_annotation(&file, location, message));
+    }
+
+    /// Whether the output path of the job is outside the output directory, unless
+    /// `--allow-escape`, such jobs are skipped
+    fn escapes_output(&self, name: &str, job: &Job) -> bool {
+        if self.opt.allow_escape
+            || self.opt.out_file.as_ref() == Some(&job.out_path)
+            || normalize_path(&job.out_path).starts_with(normalize_path(&self.opt.output))
+        {
+            return false;
+        }
+        error!(
+            "Template {}: output path {} is outside the output directory, skipping",
+            name,
+            job.out_path.to_string_lossy()
+        );
+        self.summary.lock().unwrap().skipped += 1;
+        true
+    }
+
+    /// Run `f` on each output of the templates in `names`, failures are logged and skipped
+    /// unless `--fail-fast` is set
+    fn for_each_job<'a, I, F>(&self, names: I, mut f: F) -> Result<()>
+    where
+        I: ExactSizeIterator<Item = &'a String>,
+        F: FnMut(&str, &Job) -> Result<()>,
+    {
+        let data = self.context();
+        let pb = self.progress_bar(names.len());
+        let claimed = Mutex::new(HashMap::new());
+        for name in names {
+            pb.set_message(name.to_owned());
+            pb.inc(1);
+            if let Err(e) = self.template_jobs(name, data, &claimed, &mut f) {
+                pb.finish_and_clear();
+                return Err(e);
+            }
+        }
+        pb.finish_and_clear();
+        Ok(())
+    }
+
+    /// `for_each_job` running up to `--jobs` templates at the same time
+    fn for_each_job_parallel<F>(&self, names: Vec<&String>, f: F) -> Result<()>
+    where
+        F: Fn(&str, &Job) -> Result<()> + Sync,
+    {
+        let threads = Self::threads(&self.opt);
+        if threads <= 1 || self.sets_globals() {
+            return self.for_each_job(names.into_iter(), f);
+        }
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build()?;
+        let data = self.context();
+        let pb = self.progress_bar(names.len());
+        let claimed = Mutex::new(HashMap::new());
+        let result = pool.install(|| {
+            names.par_iter().try_for_each(|name| {
+                pb.set_message(name.to_string());
+                pb.inc(1);
+                self.template_jobs(name, data, &claimed, &mut |name, job| f(name, job))
+            })
+        });
+        pb.finish_and_clear();
+        result
+    }
+
+    /// Whether a template or partial calls `setGlobal`, then templates are rendered one after
+    /// the other in name order as `setGlobal` promises
+    fn sets_globals(&self) -> bool {
+        let sets = self.engine.get_templates().iter().find(|(_, t)| {
+            let helpers = inspect::referenced_helpers(t, |name| name == "setGlobal");
+            helpers.iter().any(|name| name == "setGlobal")
+        });
+        if let Some((name, _)) = sets {
+            debug!("Rendering the templates one at a time, {} calls setGlobal", name);
+        }
+        sets.is_some()
+    }
+
+    /// Run `f` on each output of the template, a failure is logged and only returned with
+    /// `--fail-fast`
+    fn template_jobs(
+        &self,
+        name: &str,
+        data: &Value,
+        claimed: &Mutex<HashMap<PathBuf, String>>,
+        f: &mut dyn FnMut(&str, &Job) -> Result<()>,
+    ) -> Result<()> {
+        if !self.should_generate(name, data) {
+            self.summary.lock().unwrap().skipped += 1;
+            return Ok(());
+        }
+        let missing = self.missing_requirements(name, data);
+        if !missing.is_empty() && !self.opt.strict_helpers {
+            warn!("Skipping template {}, missing required values: {}", name, missing.join(", "));
+            self.summary.lock().unwrap().skipped += 1;
+            return Ok(());
+        }
+        let result = if missing.is_empty() {
+            self.jobs(name, data).map_err(anyhow::Error::from)
+        } else {
+            Err(anyhow::anyhow!("Missing required values: {}", missing.join(", ")))
+        };
+        let result = result.and_then(|jobs| {
+            jobs.iter()
+                .filter(|job| !self.escapes_output(name, job))
+                .inspect(|job| {
+                    let previous =
+                        claimed.lock().unwrap().insert(job.out_path.clone(), name.to_owned());
+                    if let Some(other) = previous.filter(|_| self.opt.lowercase_paths) {
+                        warn!(
+                            "Templates {} and {} both write {} with --lowercase-paths",
+                            other,
+                            name,
+                            job.out_path.to_string_lossy()
+                        );
+                    }
+                })
+                .try_for_each(|job| f(name, job))
+        });
+        if let Err(e) = result {
+            self.summary.lock().unwrap().fail(name, &e);
+            if self.opt.fail_fast {
+                return Err(e.context(format!("Failed to generate template {}", name)));
+            }
+            warn!("{}", self.error_report(name, &e));
+            self.annotate_failure(name, &e);
+        }
+        Ok(())
+    }
+
+    /// Render all templates into the output directory, the `concat` file and the archive
+    ///
+    /// Each template is rendered once for all of them, the output directory is only written
+    /// with `--write-files` when `--concat` or `--archive` is given.
+    pub fn render_all(&self) -> Result<()> {
+        self.helper_state.reset_globals();
+        if self.opt.output_stdout {
+            let result = self.print_all();
+            self.save_seeds()?;
+            return result;
+        }
+        let data_mtime = self.data_mtime();
+        let mut parts = vec![];
+        let mut entries = vec![];
+        let result = if self.opt.concat.is_none() && self.opt.archive.is_none() {
+            self.for_each_job_parallel(self.template_names(), |name, job| {
+                self.generate_one(name, job, data_mtime)
+            })
+        } else {
+            self.for_each_job(self.template_names().into_iter(), |name, job| {
+                let mut buf = vec![];
+                self.render_to(name, job, &mut buf)?;
+                if self.opt.concat.is_some() {
+                    parts.push(String::from_utf8_lossy(&buf).into_owned());
+                }
+                if self.opt.archive.is_some() {
+                    entries.push(self.archive_entry(name, job, buf.clone()));
+                }
+                if self.opt.write_files && !self.skip_up_to_date(name, &job.out_path, data_mtime) {
+                    self.write_or_split(name, job, buf)?;
+                }
+                Ok(())
+            })
+        };
+        // nothing else is saved when a transaction fails, as if tplgen didn't run
+        let result = self.commit_staged(result);
+        if self.staging.is_some() && result.is_err() {
+            return result;
+        }
+        if self.opt.protect_edits {
+            self.save_state()?;
+        }
+        if let Some(path) = &self.opt.cache_file {
+            self.save_cache(path)?;
+        }
+        self.save_seeds()?;
+        if let Some(algo) = &self.opt.checksums {
+            self.write_checksums(algo)?;
+        }
+        if let Some(path) = &self.opt.index {
+            self.write_index(path)?;
+        }
+        result?;
+        if let Some(path) = &self.opt.concat {
+            self.concat(path, &parts)?;
+        }
+        if let Some(path) = &self.opt.archive {
+            archive::write_archive(path, &entries)
+                .with_context(|| format!("Failed to write archive {}", path.to_string_lossy()))?;
+        }
+        Ok(())
+    }
+
+    /// Write every output to stdout after a header with its path relative to the output directory
+    ///
+    /// With `--stdout` the outputs are separated by `---` lines instead.
+    fn print_all(&self) -> Result<()> {
+        let mut first = true;
+        self.for_each_job(self.template_names().into_iter(), |name, job| {
+            let mut buf = vec![];
+            self.render_to(name, job, &mut buf)?;
+            let rel = job.out_path.strip_prefix(&self.opt.output).unwrap_or(&job.out_path);
+            let mut stdout = std::io::stdout().lock();
+            if self.opt.stdout {
+                if !first {
+                    writeln!(stdout, "---")?;
+                }
+            } else {
+                if !first {
+                    writeln!(stdout)?;
+                }
+                writeln!(stdout, "==> {} <==", rel.to_string_lossy())?;
+            }
+            first = false;
+            stdout.write_all(&buf)?;
+            if !buf.is_empty() && !buf.ends_with(b"\n") {
+                writeln!(stdout)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Render every output with `--dry-run`, printing where it would be written to stderr
+    pub fn dry_run(&self) -> Result<()> {
+        self.for_each_job(self.template_names().into_iter(), |name, job| {
+            let mut buf = vec![];
+            self.render_to(name, job, &mut buf)?;
+            let out_path = job.out_path.to_string_lossy();
+            eprintln!("{}{} => {} ({} bytes)", name, self.opt.get_ext(), out_path, buf.len());
+            Ok(())
+        })
+    }
+
+    /// Path of the file recording the digests of the outputs with `--protect-edits`
+    fn state_path(&self) -> PathBuf {
+        self.opt.output.join(".tplgen-state")
+    }
+
+    /// Key of an output in the state file, its path relative to the output directory
+    fn state_key(&self, path: &Path) -> String {
+        let rel = path.strip_prefix(&self.opt.output).unwrap_or(path);
+        let rel: Vec<_> = rel.components().map(|c| c.as_os_str().to_string_lossy()).collect();
+        rel.join("/")
+    }
+
+    /// Digests recorded by the last run, empty if there is no state file
+    fn load_state(&self) -> BTreeMap<String, String> {
+        let path = self.state_path();
+        let text = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(_) => return Default::default(),
+        };
+        serde_json::from_str(&text)
+            .with_context(|| format!("Invalid state file {}", path.to_string_lossy()))
+            .log()
+            .unwrap_or_default()
+    }
+
+    /// Record the digest of an output about to be written
+    fn record_state(&self, path: &Path, content: &[u8]) {
+        if self.opt.protect_edits {
+            let key = self.state_key(path);
+            self.state.lock().unwrap().insert(key, hex_digest("sha256", content));
+        }
+    }
+
+    /// Whether an existing output differs from what the last run wrote, outputs that were
+    /// never recorded count as edited, always false without `--protect-edits`
+    fn edited(&self, path: &Path) -> bool {
+        if !self.opt.protect_edits {
+            return false;
+        }
+        let recorded = self.state.lock().unwrap().get(&self.state_key(path)).cloned();
+        match (recorded, std::fs::read(path)) {
+            (Some(recorded), Ok(content)) => recorded != hex_digest("sha256", &content),
+            _ => true,
+        }
+    }
+
+    /// Write the digests of the outputs into the state file
+    fn save_state(&self) -> Result<()> {
+        let path = self.state_path();
+        let state = serde_json::to_string_pretty(&*self.state.lock().unwrap())?;
+        std::fs::create_dir_all(&self.opt.output).log().ok();
+        std::fs::write(&path, state + "\n")
+            .with_context(|| format!("Failed to write state file {}", path.to_string_lossy()))
+    }
+
+    /// Entries of the `--cache-file` written by this version of tplgen, none otherwise
+    fn load_cache(path: &Path) -> BTreeMap<String, CacheEntry> {
+        let cache = match std::fs::read_to_string(path) {
+            Ok(text) => serde_json::from_str::<Cache>(&text)
+                .with_context(|| format!("Invalid cache file {}", path.to_string_lossy()))
+                .log()
+                .unwrap_or_default(),
+            Err(_) => return Default::default(),
+        };
+        if cache.version != env!("CARGO_PKG_VERSION") {
+            info!("Ignoring the cache file written by tplgen {}", cache.version);
+            return Default::default();
+        }
+        cache.entries
+    }
+
+    /// Write the `--cache-file`
+    fn save_cache(&self, path: &Path) -> Result<()> {
+        let cache = Cache {
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            entries: self.cache.lock().unwrap().clone(),
+        };
+        std::fs::write(path, serde_json::to_string_pretty(&cache)? + "\n")
+            .with_context(|| format!("Failed to write cache file {}", path.to_string_lossy()))
+    }
+
+    /// Values of `randomSecret` stored in the `--seed-file`, none if it doesn't exist yet
+    fn load_seeds(path: &Path) -> Result<BTreeMap<String, String>> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => serde_json::from_str(&text)
+                .with_context(|| format!("Invalid seed file {}", path.to_string_lossy())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Default::default()),
+            Err(e) => Err(e)
+                .with_context(|| format!("Failed to read seed file {}", path.to_string_lossy())),
+        }
+    }
+
+    /// Write the values of `randomSecret` into the `--seed-file` when new ones were generated
+    fn save_seeds(&self) -> Result<()> {
+        let path = match &self.opt.seed_file {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let seeds = self.helper_state.seeds();
+        if Self::load_seeds(path).ok().as_ref() == Some(&seeds) {
+            return Ok(());
+        }
+        info!("Random secrets => {}", path.to_string_lossy());
+        std::fs::write(path, serde_json::to_string_pretty(&seeds)? + "\n")
+            .and_then(|_| set_mode(path, 0o600))
+            .with_context(|| format!("Failed to write seed file {}", path.to_string_lossy()))
+    }
+
+    /// Write the digests of the outputs in the output directory into `SHA256SUMS` or
+    /// `SHA512SUMS`, in the format of `sha256sum`
+    fn write_checksums(&self, algo: &str) -> Result<()> {
+        let sums_path = self.opt.output.join(format!("{}SUMS", algo.to_uppercase()));
+        let mut outputs = self.summary.lock().unwrap().outputs.clone();
+        outputs.sort();
+        outputs.dedup();
+        let mut sums = String::new();
+        for path in outputs.iter().filter(|p| **p != sums_path) {
+            let content = std::fs::read(path)
+                .with_context(|| format!("Failed to read {}", path.to_string_lossy()))?;
+            let hex = hex_digest(algo, &content);
+            let rel = path.strip_prefix(&self.opt.output).unwrap_or(path);
+            let rel: Vec<_> = rel.components().map(|c| c.as_os_str().to_string_lossy()).collect();
+            sums.push_str(&format!("{}  {}\n", hex, rel.join("/")));
+        }
+        info!("Checksums of the outputs => {}", sums_path.to_string_lossy());
+        std::fs::create_dir_all(&self.opt.output).log().ok();
+        std::fs::write(&sums_path, sums)
+            .with_context(|| format!("Failed to write {}", sums_path.to_string_lossy()))
+    }
+
+    /// Render the list of the outputs in the output directory into the `--index`
+    fn write_index(&self, index: &Path) -> Result<()> {
+        let summary = self.summary.lock().unwrap();
+        let mut outputs: Vec<_> = summary.output_templates.iter().zip(&summary.outputs).collect();
+        // templates rendered in parallel finish in any order
+        let order: HashMap<&String, usize> =
+            self.template_names().into_iter().enumerate().map(|(i, name)| (name, i)).collect();
+        outputs.sort_by_key(|(name, _)| order.get(name).copied().unwrap_or(usize::MAX));
+        let index_path = normalize_path(index);
+        let mut seen = HashSet::new();
+        let files: Vec<_> = outputs
+            .into_iter()
+            .map(|(_, path)| path)
+            .filter(|p| normalize_path(p) != index_path && seen.insert(*p))
+            .map(|path| {
+                let rel = path.strip_prefix(&self.opt.output).unwrap_or(path);
+                let rel: Vec<_> =
+                    rel.components().map(|c| c.as_os_str().to_string_lossy()).collect();
+                serde_json::json!({
+                    "path": rel.join("/"),
+                    "name": path.file_name().unwrap_or_default().to_string_lossy(),
+                    "size": std::fs::metadata(path).map(|m| m.len()).unwrap_or(0),
+                })
+            })
+            .collect();
+        let bytes: u64 = files.iter().filter_map(|f| f["size"].as_u64()).sum();
+        let data = serde_json::json!({ "files": files, "count": files.len(), "bytes": bytes });
+        let template = match &self.opt.index_template {
+            Some(path) => std::fs::read_to_string(path).with_context(|| {
+                format!("Failed to read index template {}", path.to_string_lossy())
+            })?,
+            None => DEFAULT_INDEX.to_owned(),
+        };
+        let content = self
+            .engine
+            .render_template(&template, &data)
+            .context("Failed to render the index")?;
+        info!("Index of {} outputs => {}", files.len(), index.to_string_lossy());
+        if let Some(parent) = index.parent().filter(|p| !p.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent).log().ok();
+        }
+        std::fs::write(index, content)
+            .with_context(|| format!("Failed to write index {}", index.to_string_lossy()))
+    }
+
+    /// Entry of a rendered output in the archive, the path is relative to the output directory
+    fn archive_entry(&self, name: &str, job: &Job, content: Vec<u8>) -> archive::Entry {
+        let rel = job.out_path.strip_prefix(&self.opt.output).unwrap_or(&job.out_path);
+        let path = rel
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        info!("{}{} => {}", name, self.opt.get_ext(), path);
+        let mode = self.mode(name, &job.out_path).unwrap_or(0o644);
+        archive::Entry { path, content, mode }
+    }
+
+    /// Number of templates that failed to parse or to render so far
+    pub fn failed(&self) -> usize {
+        self.scan.errors.len() + self.summary.lock().unwrap().failed
+    }
+
+    /// One line summary of the run, like `3 output(s) generated, 1 unchanged, 1 failed`
+    pub fn summary_line(&self) -> String {
+        let summary = self.summary.lock().unwrap();
+        format!(
+            "{} output(s) generated, {} unchanged, {} failed",
+            summary.created + summary.updated,
+            summary.skipped,
+            self.scan.errors.len() + summary.failed
+        )
+    }
+
+    /// Run the post hook through the shell with `TPLGEN_HOOK_OUTPUT`, `TPLGEN_HOOK_FILES`,
+    /// counting outputs written or up to date, and `TPLGEN_HOOK_FAILED` in its environment
+    pub fn run_post_hook(&self, cmd: &str) -> Result<std::process::ExitStatus> {
+        info!("Running post hook {}", cmd);
+        let mut command = if cfg!(windows) {
+            let mut c = std::process::Command::new("cmd");
+            c.args(["/C", cmd]);
+            c
+        } else {
+            let mut c = std::process::Command::new("sh");
+            c.args(["-c", cmd]);
+            c
+        };
+        let files = self.summary.lock().unwrap().outputs.len();
+        command
+            .env("TPLGEN_HOOK_OUTPUT", &self.opt.output)
+            .env("TPLGEN_HOOK_FILES", files.to_string())
+            .env("TPLGEN_HOOK_FAILED", self.failed().to_string());
+        command
+            .status()
+            .with_context(|| format!("Failed to run post hook {}", cmd))
+    }
+
+    /// Write the summary as JSON to a file, `-` for stderr
+    pub fn write_summary(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_string_pretty(&*self.summary.lock().unwrap())?;
+        if path == Path::new("-") {
+            eprintln!("{}", json);
+        } else {
+            std::fs::write(path, json)?;
+        }
+        Ok(())
+    }
+
+    /// Write the rendered outputs into one file, `-` for stdout
+    fn concat(&self, path: &Path, parts: &[String]) -> Result<()> {
+        let content = parts.join(&self.opt.concat_separator);
+        if path == Path::new("-") {
+            std::io::stdout().write_all(content.as_bytes())?;
+        } else {
+            info!("Concatenated {} outputs => {}", parts.len(), path.to_string_lossy());
+            std::fs::write(path, content)
+                .with_context(|| format!("Failed to write output file {}", path.to_string_lossy()))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn merged_defaults() -> serde_json::Map<String, Value> {
+        match to_value(Opt::default()) {
+            Ok(Value::Object(m)) => m,
+            _ => unreachable!("options are serialized as a map"),
+        }
+    }
+
+    fn env(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn merge_env_coerces_optional_numbers() {
+        let mut merged = merged_defaults();
+        let vars = env(&[("TPLGEN_JOBS", "2"), ("TPLGEN_MTIME", "5"), ("TPLGEN_CHMOD", "644")]);
+        let warnings = merge_env(&mut merged, vars, |_| false);
+        assert!(warnings.is_empty());
+        let opt: Opt = serde_json::from_value(Value::Object(merged)).unwrap();
+        assert_eq!(opt.jobs, Some(2));
+        assert_eq!(opt.mtime, Some(5));
+        assert_eq!(opt.chmod.as_deref(), Some("644"));
+    }
+
+    #[test]
+    fn merge_env_skips_given_and_warns_unknown() {
+        let mut merged = merged_defaults();
+        let vars = env(&[("TPLGEN_JOBS", "2"), ("TPLGEN_NOPE", "1"), ("OTHER", "1")]);
+        let warnings = merge_env(&mut merged, vars, |k| k == "jobs");
+        assert_eq!(warnings, ["Unknown option in environment variable TPLGEN_NOPE"]);
+        assert_eq!(merged["jobs"], Value::Null);
+    }
+
+    #[test]
+    fn nested_env_values_win_over_flat_ones() {
+        let mut data = serde_json::json!({});
+        for (k, v) in [("database", "x"), ("DATABASE__HOST", "y"), ("database", "z")] {
+            if let Some(v) = nested_env_value(&data, &env_keys(k, Some("__")), Value::from(v), k) {
+                merge(&mut data, v);
+            }
+        }
+        assert_eq!(data, serde_json::json!({"database": {"host": "y"}}));
+    }
+
+    fn merged_yaml(text: &str) -> Value {
+        let yaml: serde_yaml::Value = serde_yaml::from_str(text).unwrap();
+        to_value(expand_merge_keys(yaml)).unwrap()
+    }
+
+    #[test]
+    fn merge_keys_are_expanded_under_own_keys() {
+        let v = merged_yaml("base: &base {a: 1, b: 2}\nprod: {<<: *base, b: 3}\n");
+        assert_eq!(v["prod"], serde_json::json!({"a": 1, "b": 3}));
+        assert!(v["prod"].get("<<").is_none());
+    }
+
+    #[test]
+    fn earlier_merged_mappings_win() {
+        let v = merged_yaml("x: &x {a: 1}\ny: &y {a: 2, b: 2}\nz: {<<: [*x, *y]}\n");
+        assert_eq!(v["z"], serde_json::json!({"a": 1, "b": 2}));
+    }
+
+    #[test]
+    fn merge_keys_are_expanded_in_sequences_and_non_maps_ignored() {
+        let v = merged_yaml("d: &d {a: 1}\nl: [{<<: *d, b: 2}]\nbad: {<<: 3, c: 1}\n");
+        assert_eq!(v["l"], serde_json::json!([{"a": 1, "b": 2}]));
+        assert_eq!(v["bad"], serde_json::json!({"c": 1}));
+    }
+
+    fn toml_values(text: &str) -> Option<Value> {
+        Generator::parse_values(text.as_bytes(), "toml", MergeMode::Deep)
+    }
+
+    #[test]
+    fn toml_values_convert_dates_and_tables() {
+        let v = toml_values("when = 1979-05-27T07:32:00Z\n[db]\nport = 5432\nratio = 0.5\n");
+        let expected = serde_json::json!({
+            "when": "1979-05-27T07:32:00Z",
+            "db": {"port": 5432, "ratio": 0.5},
+        });
+        assert_eq!(v, Some(expected));
+        let v = toml_values("[[servers]]\nname = \"a\"\n[[servers]]\nname = \"b\"\n");
+        assert_eq!(v, Some(serde_json::json!({"servers": [{"name": "a"}, {"name": "b"}]})));
+    }
+
+    #[test]
+    fn toml_values_merge_over_other_values() {
+        let mut data = serde_json::json!({"db": {"host": "h", "port": 1}});
+        MergeMode::Deep.merge(&mut data, toml_values("[db]\nport = 2\n").unwrap());
+        assert_eq!(data, serde_json::json!({"db": {"host": "h", "port": 2}}));
+    }
+
+    #[test]
+    fn invalid_toml_values_are_none() {
+        assert_eq!(toml_values("a = \n"), None);
+        assert_eq!(toml_values("a = 1\na = 2\n"), None);
+    }
+
+    #[test]
+    fn toml_value_files_are_read_by_extension() {
+        let path = std::env::temp_dir().join(format!("tplgen-{}.TOML", std::process::id()));
+        std::fs::write(&path, "a = true\n").unwrap();
+        let v = Generator::read_values_file(&path, MergeMode::Deep);
+        std::fs::remove_file(&path).ok();
+        assert_eq!(v, Some(serde_json::json!({"a": true})));
+    }
+
+    #[test]
+    fn default_options_are_the_command_line_ones() {
+        let opt = Opt::default();
+        assert_eq!(opt.extension, ".hbs");
+        assert_eq!(opt.output, Path::new("."));
+        assert!(opt.input.is_empty() && !opt.strict);
+        let opt = Opt::parse(["-i", "values.yaml", "--strict", "templates"]).unwrap();
+        assert_eq!(opt.values, [Path::new("values.yaml")]);
+        assert_eq!(opt.input, [Path::new("templates")]);
+        assert!(opt.strict);
+        assert!(Opt::parse(["--no-such-option"]).is_err());
+    }
+
+    #[test]
+    fn empty_yaml_values_are_an_empty_map() {
+        let empty = serde_json::json!({});
+        for text in ["", "# nothing yet\n", "---\n"] {
+            let v = Generator::parse_values(text.as_bytes(), "yaml", MergeMode::Deep);
+            assert_eq!(v.as_ref(), Some(&empty), "{:?}", text);
+        }
+        let v = Generator::parse_values("a: 1\n---\nb: 2\n".as_bytes(), "yml", MergeMode::Deep);
+        assert_eq!(v, Some(serde_json::json!({"a": 1, "b": 2})));
+        let missing = format!("tplgen-{}-missing.yaml", std::process::id());
+        let missing = std::env::temp_dir().join(missing);
+        assert_eq!(Generator::read_values_file(&missing, MergeMode::Deep), None);
+    }
+
+    #[test]
+    fn flat_env_keys_keep_their_case() {
+        assert_eq!(env_keys("PATH", Some("__")), ["PATH"]);
+        assert_eq!(env_keys("APP__NAME", Some("__")), ["app", "name"]);
+        assert_eq!(env_keys("APP__NAME", None), ["APP__NAME"]);
+        let mut data = serde_json::json!({});
+        for (k, v) in [("PATH", "/bin"), ("APP__NAME", "x")] {
+            if let Some(v) = nested_env_value(&data, &env_keys(k, Some("__")), Value::from(v), k) {
+                merge(&mut data, v);
+            }
+        }
+        assert_eq!(data, serde_json::json!({"PATH": "/bin", "app": {"name": "x"}}));
+    }
+}
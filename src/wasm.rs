@@ -0,0 +1,80 @@
+use anyhow::{anyhow, Context as _, Result};
+use handlebars::{Context, Handlebars, Helper, HelperDef, RenderContext, RenderError, ScopedJson};
+use log::warn;
+use serde_json::Value;
+use std::path::Path;
+use wasmtime::{Engine, Instance, Memory, Module, Store, TypedFunc};
+
+/// A helper implemented by a WebAssembly module
+///
+/// The module exports its `memory`, `alloc(len: i32) -> i32` returning a buffer for the input,
+/// and `helper(ptr: i32, len: i32) -> i64` taking the input and returning the output as
+/// `ptr << 32 | len`. The input is the JSON object `{"params": [...], "hash": {...}}` and the
+/// output is the UTF-8 text written by the helper. Each call gets a fresh instance, so calls
+/// don't share any state.
+pub struct WasmHelper {
+    name: String,
+    engine: Engine,
+    module: Module,
+}
+
+impl WasmHelper {
+    /// Compile the module, the helper is named after the file stem
+    pub fn load(path: &Path) -> Result<Self> {
+        let engine = Engine::default();
+        let module = Module::from_file(&engine, path)
+            .map_err(anyhow::Error::from)
+            .with_context(|| format!("Cannot load helper {}", path.to_string_lossy()))?;
+        let name = path.file_stem().unwrap_or_default().to_string_lossy().into_owned();
+        Ok(Self {
+            name,
+            engine,
+            module,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn run(&self, input: &[u8]) -> Result<String> {
+        let mut store = Store::new(&self.engine, ());
+        let instance = Instance::new(&mut store, &self.module, &[])?;
+        let memory: Memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| anyhow!("no exported memory"))?;
+        let alloc: TypedFunc<i32, i32> = instance.get_typed_func(&mut store, "alloc")?;
+        let helper: TypedFunc<(i32, i32), i64> = instance.get_typed_func(&mut store, "helper")?;
+        let len = i32::try_from(input.len())?;
+        let ptr = alloc.call(&mut store, len)?;
+        memory.write(&mut store, ptr as u32 as usize, input)?;
+        let result = helper.call(&mut store, (ptr, len))? as u64;
+        let (ptr, len) = ((result >> 32) as usize, (result & 0xffff_ffff) as usize);
+        let mut out = vec![0; len];
+        memory.read(&store, ptr, &mut out)?;
+        Ok(String::from_utf8(out)?)
+    }
+}
+
+impl HelperDef for WasmHelper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'reg, 'rc>, RenderError> {
+        let params: Vec<Value> = h.params().iter().map(|p| p.value().clone()).collect();
+        let hash: serde_json::Map<String, Value> = h
+            .hash()
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.value().clone()))
+            .collect();
+        let input = serde_json::json!({ "params": params, "hash": hash }).to_string();
+        let output = self.run(input.as_bytes()).unwrap_or_else(|e| {
+            warn!("{}: WebAssembly helper failed, {:#}", self.name, e);
+            String::new()
+        });
+        Ok(ScopedJson::Derived(Value::String(output)))
+    }
+}